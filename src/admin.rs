@@ -0,0 +1,154 @@
+use std::net::IpAddr;
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use log::{info, warn};
+use serde::Deserialize;
+use crate::audit;
+use crate::backup;
+use crate::ban::{Ban, BANS};
+use crate::config::{self, CONFIG_INSTANCE};
+use crate::fsck;
+use crate::reload_geoip_databases;
+use crate::retention::{self, RETENTION_METRICS};
+use crate::util::try_get_username_token;
+
+#[derive(Deserialize)]
+pub struct AdminAuth {
+    pub username: String,
+    pub token: String,
+}
+
+/// An admin is a logged in user listed in `admin_users`.
+pub fn is_admin(auth: &AdminAuth) -> bool {
+    let is_admin_user = CONFIG_INSTANCE.load().settings.admin_users.contains(&auth.username);
+    if !is_admin_user || !try_get_username_token(&auth.username, auth.token.clone()) {
+        warn!("Rejected admin request from {}", auth.username);
+        return false;
+    }
+    true
+}
+
+pub async fn reload_geoip(Query(auth): Query<AdminAuth>) -> (StatusCode, Json<String>) {
+    if !is_admin(&auth) {
+        return (StatusCode::UNAUTHORIZED, Json("Not admin".to_string()));
+    }
+    info!("GeoIP reload requested by {}", auth.username);
+    match reload_geoip_databases().await {
+        Ok(message) => (StatusCode::OK, Json(message)),
+        Err(e) => (StatusCode::UNPROCESSABLE_ENTITY, Json(format!("Keep current table: {}", e))),
+    }
+}
+
+pub async fn reload_config(Query(auth): Query<AdminAuth>) -> (StatusCode, Json<Vec<String>>) {
+    if !is_admin(&auth) {
+        return (StatusCode::UNAUTHORIZED, Json(vec!["Not admin".to_string()]));
+    }
+    info!("Config reload requested by {}", auth.username);
+    match config::reload() {
+        Ok(changes) => (StatusCode::OK, Json(changes)),
+        Err(e) => (StatusCode::UNPROCESSABLE_ENTITY, Json(vec![format!("Keep current config: {}", e)])),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RetentionRun {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Runs the history retention now, `?dry_run=true` only reports.
+pub async fn run_retention(Query(auth): Query<AdminAuth>, Query(run): Query<RetentionRun>) -> Response {
+    if !is_admin(&auth) {
+        return (StatusCode::UNAUTHORIZED, Json("Not admin".to_string())).into_response();
+    }
+    info!("Retention run requested by {}, dry run: {}", auth.username, run.dry_run);
+    match retention::run_recorded(run.dry_run).await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(format!("Retention failed: {}", e))).into_response(),
+    }
+}
+
+pub async fn retention_metrics(Query(auth): Query<AdminAuth>) -> Response {
+    if !is_admin(&auth) {
+        return (StatusCode::UNAUTHORIZED, Json("Not admin".to_string())).into_response();
+    }
+    let metrics = RETENTION_METRICS.lock().unwrap().clone();
+    (StatusCode::OK, Json(metrics)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    #[serde(default = "default_audit_limit")]
+    pub limit: usize,
+}
+
+fn default_audit_limit() -> usize {
+    100
+}
+
+/// The newest audit events, e.g. recovered tabs files, `?limit=` defaults to 100.
+pub async fn list_audit_events(Query(auth): Query<AdminAuth>, Query(query): Query<AuditQuery>) -> Response {
+    if !is_admin(&auth) {
+        return (StatusCode::UNAUTHORIZED, Json("Not admin".to_string())).into_response();
+    }
+    match audit::recent(query.limit) {
+        Ok(events) => (StatusCode::OK, Json(events)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(format!("Error reading audit log: {}", e))).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FsckRun {
+    #[serde(default)]
+    pub repair: bool,
+}
+
+/// Checks the data directory while serving, `?repair=true` also repairs.
+pub async fn run_fsck(Query(auth): Query<AdminAuth>, Query(run): Query<FsckRun>) -> Response {
+    if !is_admin(&auth) {
+        return (StatusCode::UNAUTHORIZED, Json("Not admin".to_string())).into_response();
+    }
+    info!("Fsck requested by {}, repair: {}", auth.username, run.repair);
+    match fsck::run_online(run.repair).await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(format!("Fsck failed: {}", e))).into_response(),
+    }
+}
+
+/// Writes a backup archive to `backup_dir` while serving and returns where.
+pub async fn create_backup(Query(auth): Query<AdminAuth>) -> Response {
+    if !is_admin(&auth) {
+        return (StatusCode::UNAUTHORIZED, Json("Not admin".to_string())).into_response();
+    }
+    info!("Backup requested by {}", auth.username);
+    match backup::run_online().await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(format!("Backup failed: {}", e))).into_response(),
+    }
+}
+
+pub async fn list_bans(Query(auth): Query<AdminAuth>) -> (StatusCode, Json<Vec<Ban>>) {
+    if !is_admin(&auth) {
+        return (StatusCode::UNAUTHORIZED, Json(Vec::new()));
+    }
+    let bans = BANS.lock().unwrap().list(chrono::Utc::now().timestamp());
+    (StatusCode::OK, Json(bans))
+}
+
+pub async fn unban(Path(ip): Path<String>, Query(auth): Query<AdminAuth>) -> (StatusCode, Json<String>) {
+    if !is_admin(&auth) {
+        return (StatusCode::UNAUTHORIZED, Json("Not admin".to_string()));
+    }
+    let ip = match ip.parse::<IpAddr>() {
+        Ok(value) => value,
+        Err(_) => return (StatusCode::BAD_REQUEST, Json(format!("Invalid ip: {}", ip))),
+    };
+    info!("Unban {} requested by {}", ip, auth.username);
+    if BANS.lock().unwrap().unban(ip) {
+        (StatusCode::OK, Json("OK".to_string()))
+    } else {
+        (StatusCode::NOT_FOUND, Json(format!("{} is not banned", ip)))
+    }
+}
@@ -0,0 +1,87 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use log::error;
+use serde::{Deserialize, Serialize};
+use crate::util::data_path;
+
+lazy_static! {
+    // one appender at a time, so lines never interleave
+    static ref AUDIT_LOG: Mutex<()> = Mutex::new(());
+}
+
+/// Something the operator should know about that happened to the data, one json line each
+/// in `data/audit.log`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    pub time: i64,
+    pub event: String,
+    pub username: String,
+    #[serde(default)]
+    pub details: serde_json::Value,
+}
+
+fn append(path: &Path, event: &AuditEvent) -> Result<(), std::io::Error> {
+    let _audit_log = AUDIT_LOG.lock().unwrap();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(format!("{}\n", serde_json::to_string(event).unwrap()).as_bytes())?;
+    file.sync_all()
+}
+
+/// Appends an event, a failure is logged and does not fail the caller.
+pub fn record(event: &str, username: &str, details: serde_json::Value) {
+    let event = AuditEvent {
+        time: chrono::Utc::now().timestamp(),
+        event: event.to_string(),
+        username: username.to_string(),
+        details,
+    };
+    if let Err(e) = append(&data_path("audit.log"), &event) {
+        error!("Error writing audit event {:?}: {}", event, e);
+    }
+}
+
+fn read(path: &Path, limit: usize) -> Result<Vec<AuditEvent>, std::io::Error> {
+    let file = match File::open(path) {
+        Ok(value) => value,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut events = Vec::new();
+    for line in BufReader::new(file).lines() {
+        // a line cut off by a crash is skipped
+        if let Ok(event) = serde_json::from_str::<AuditEvent>(&line?) {
+            events.push(event);
+        }
+    }
+    let skip = events.len().saturating_sub(limit);
+    Ok(events.split_off(skip))
+}
+
+/// The newest `limit` events, oldest first.
+pub fn recent(limit: usize) -> Result<Vec<AuditEvent>, std::io::Error> {
+    read(&data_path("audit.log"), limit)
+}
+
+// test module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_and_read() {
+        let path = std::env::temp_dir().join(format!("bot-audit-{}.log", std::process::id()));
+        assert_eq!(read(&path, 10).unwrap(), Vec::new());
+        for (time, username) in [(1, "alice"), (2, "bob"), (3, "carol")] {
+            let event = AuditEvent { time, event: String::from("tabs_recovered"), username: username.to_string(), details: serde_json::json!({ "snapshot": time }) };
+            append(&path, &event).unwrap();
+        }
+        std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"time\":4,").unwrap();
+
+        let events = read(&path, 2).unwrap();
+        assert_eq!(events.iter().map(|event| event.username.as_str()).collect::<Vec<&str>>(), vec!["bob", "carol"]);
+        assert_eq!(events[1].details["snapshot"], 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
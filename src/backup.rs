@@ -0,0 +1,531 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use log::error;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::audit;
+use crate::ban::BANS;
+use crate::config::{config_source, CONFIG_INSTANCE};
+use crate::fsck::{self, IssueKind};
+use crate::history;
+use crate::labels::LABELS;
+use crate::util::{generate_random_string, lock_file, write_atomic};
+
+const MANIFEST: &str = "manifest.json";
+const FORMAT_VERSION: u32 = 1;
+// archives are written while serving, favour speed over size
+const COMPRESSION_LEVEL: i32 = 3;
+// files appended in place instead of replaced, they are copied instead of linked
+const APPENDED_FILES: [&str; 1] = ["audit.log"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BackupFile {
+    // `data/...` or `config/...`
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// The first entry of every archive.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BackupManifest {
+    pub version: u32,
+    pub created_at: i64,
+    pub server_version: String,
+    pub files: Vec<BackupFile>,
+    // empty directories, git repositories need them
+    #[serde(default)]
+    pub dirs: Vec<String>,
+}
+
+impl BackupManifest {
+    pub fn bytes(&self) -> u64 {
+        self.files.iter().map(|file| file.size).sum()
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct BackupReport {
+    pub archive: String,
+    pub created_at: i64,
+    pub files: usize,
+    pub bytes: u64,
+    pub archive_bytes: u64,
+}
+
+impl fmt::Display for BackupReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Wrote {}: {} files, {} bytes, {} bytes compressed", self.archive, self.files, self.bytes, self.archive_bytes)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct RestoreReport {
+    pub archive: String,
+    pub created_at: i64,
+    pub files: usize,
+    // the replaced data directory contents
+    pub previous: String,
+    pub config: Vec<String>,
+}
+
+impl fmt::Display for RestoreReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Restored {} files of the backup from {} in {}, the previous data is in {}", self.files, self.created_at, self.archive, self.previous)?;
+        for config in &self.config {
+            write!(f, "\nRestored {}", config)?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn relative_path(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root).unwrap().components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// Files and empty directories below `dir` relative to `root` with `/` separators,
+/// dotfiles like temp files are skipped.
+fn list_files(root: &Path, dir: &Path, files: &mut Vec<String>, dirs: &mut Vec<String>) -> Result<(), Error> {
+    let mut empty = true;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            list_files(root, &entry.path(), files, dirs)?;
+            empty = false;
+        } else if file_type.is_file() {
+            files.push(relative_path(root, &entry.path()));
+            empty = false;
+        }
+    }
+    if empty && dir != root {
+        dirs.push(relative_path(root, dir));
+    }
+    Ok(())
+}
+
+fn checksum(path: &Path) -> Result<(u64, String), Error> {
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+/// Links the files of `data_dir` into `staging`. Writers replace files by renaming, so a link
+/// keeps the content of this moment. Syncs, labels and the object store wait meanwhile, bans only
+/// while their file is linked, since every request checks them.
+fn stage_data(data_dir: &Path, staging: &Path) -> Result<(), Error> {
+    let tabs_locks = fs::read_dir(data_dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| name.ends_with(".json"))
+        .map(|name| lock_file(&name))
+        .collect::<Vec<_>>();
+    let _tabs = tabs_locks.iter().map(|lock| lock.lock().unwrap()).collect::<Vec<_>>();
+    let _labels = LABELS.lock().unwrap();
+    let _store = history::lock_store();
+
+    let (mut files, mut dirs) = (Vec::new(), Vec::new());
+    list_files(data_dir, data_dir, &mut files, &mut dirs)?;
+    for dir in dirs {
+        fs::create_dir_all(staging.join(dir))?;
+    }
+    for file in files {
+        let (from, to) = (data_dir.join(&file), staging.join(&file));
+        fs::create_dir_all(to.parent().unwrap())?;
+        let _bans = (file == "bans.json").then(|| BANS.lock().unwrap());
+        // another file system, or a file system without links
+        if APPENDED_FILES.contains(&file.as_str()) || fs::hard_link(&from, &to).is_err() {
+            fs::copy(&from, &to)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_archive(staging: &Path, manifest: &BackupManifest, archive: &Path) -> Result<(), Error> {
+    let mut builder = tar::Builder::new(zstd::Encoder::new(File::create(archive)?, COMPRESSION_LEVEL)?);
+    let contents = serde_json::to_vec_pretty(manifest).unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.created_at as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST, contents.as_slice())?;
+    for dir in &manifest.dirs {
+        builder.append_dir(dir, staging.join(dir))?;
+    }
+    for file in &manifest.files {
+        builder.append_path_with_name(staging.join(&file.path), &file.path)?;
+    }
+    builder.into_inner()?.finish()?.sync_all()
+}
+
+fn stage_and_write(data_dir: &Path, config_files: &[(String, PathBuf)], staging: &Path, created_at: i64, archive: &Path) -> Result<BackupManifest, Error> {
+    stage_data(data_dir, &staging.join("data"))?;
+    for (name, path) in config_files {
+        fs::create_dir_all(staging.join("config"))?;
+        fs::copy(path, staging.join("config").join(name))?;
+    }
+    let (mut paths, mut dirs) = (Vec::new(), Vec::new());
+    list_files(staging, staging, &mut paths, &mut dirs)?;
+    paths.sort();
+    dirs.sort();
+    let mut files = Vec::new();
+    for path in paths {
+        let (size, sha256) = checksum(&staging.join(&path))?;
+        files.push(BackupFile { path, size, sha256 });
+    }
+    let manifest = BackupManifest { version: FORMAT_VERSION, created_at, server_version: env!("CARGO_PKG_VERSION").to_string(), files, dirs };
+    write_archive(staging, &manifest, archive)?;
+    Ok(manifest)
+}
+
+/// Writes a point-in-time archive of `data_dir` and `config_files` (name, path) to `archive`.
+pub fn create(data_dir: &Path, config_files: &[(String, PathBuf)], archive: &Path) -> Result<BackupReport, Error> {
+    let created_at = chrono::Utc::now().timestamp();
+    let staging = data_dir.join(format!(".backup-{}-{}", created_at, generate_random_string(8)));
+    let name = archive.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let temp_path = archive.with_file_name(format!(".{}.{}.tmp", name, generate_random_string(8)));
+    let result = stage_and_write(data_dir, config_files, &staging, created_at, &temp_path)
+        .and_then(|manifest| fs::rename(&temp_path, archive).map(|()| manifest));
+    let _ = fs::remove_dir_all(&staging);
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    let manifest = result?;
+    Ok(BackupReport {
+        archive: archive.to_string_lossy().into_owned(),
+        created_at,
+        files: manifest.files.len(),
+        bytes: manifest.bytes(),
+        archive_bytes: fs::metadata(archive)?.len(),
+    })
+}
+
+/// Unpacks `archive` into `target` and checks it against its manifest: no file is missing,
+/// extra or changed, and every tabs file and snapshot parses.
+pub fn extract(archive: &Path, target: &Path) -> Result<BackupManifest, Error> {
+    fs::create_dir_all(target)?;
+    let mut entries = tar::Archive::new(zstd::Decoder::new(File::open(archive)?)?);
+    for entry in entries.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        if !entry.header().entry_type().is_file() && !entry.header().entry_type().is_dir() {
+            return Err(invalid(format!("{} is not a file", path)));
+        }
+        if !entry.unpack_in(target)? {
+            return Err(invalid(format!("{} is outside the archive", path)));
+        }
+    }
+
+    let contents = fs::read_to_string(target.join(MANIFEST)).map_err(|e| invalid(format!("No {}: {}", MANIFEST, e)))?;
+    let manifest: BackupManifest = serde_json::from_str(&contents).map_err(|e| invalid(format!("Invalid {}: {}", MANIFEST, e)))?;
+    if manifest.version > FORMAT_VERSION {
+        return Err(invalid(format!("Archive version {} is newer than {}", manifest.version, FORMAT_VERSION)));
+    }
+    let (mut files, mut dirs) = (Vec::new(), Vec::new());
+    list_files(target, target, &mut files, &mut dirs)?;
+    let listed = manifest.files.iter().map(|file| file.path.as_str()).collect::<HashSet<&str>>();
+    if let Some(file) = files.iter().find(|file| file.as_str() != MANIFEST && !listed.contains(file.as_str())) {
+        return Err(invalid(format!("{} is not in the manifest", file)));
+    }
+    for file in &manifest.files {
+        let (size, sha256) = checksum(&target.join(&file.path)).map_err(|e| invalid(format!("{}: {}", file.path, e)))?;
+        if size != file.size || sha256 != file.sha256 {
+            return Err(invalid(format!("{} does not match its checksum", file.path)));
+        }
+    }
+
+    let data_dir = target.join("data");
+    if !data_dir.join("users.txt").exists() {
+        return Err(invalid(String::from("No data/users.txt")));
+    }
+    let report = fsck::check(&data_dir, false)?;
+    let corrupt = report.issues.iter()
+        .filter(|issue| matches!(issue.kind, IssueKind::CorruptTabs | IssueKind::CorruptSnapshot))
+        .map(|issue| format!("{}: {}", issue.path, issue.message))
+        .collect::<Vec<String>>();
+    if !corrupt.is_empty() {
+        return Err(invalid(format!("Corrupt files: {}", corrupt.join("; "))));
+    }
+    Ok(manifest)
+}
+
+/// The settings file and the logging config, as they are named in the archive.
+fn config_files() -> Vec<(String, PathBuf)> {
+    let source = config_source();
+    source.config_file().into_iter()
+        .chain(Some(source.config_dir().join("log4rs.yaml")))
+        .filter(|path| path.is_file())
+        .map(|path| (path.file_name().unwrap().to_string_lossy().into_owned(), path))
+        .collect()
+}
+
+/// Backs up the configured data directory and settings, to `backup_dir` unless `output` is given.
+pub fn run(output: Option<PathBuf>) -> Result<BackupReport, Error> {
+    let settings = CONFIG_INSTANCE.load().settings.clone();
+    let archive = match output {
+        Some(value) => value,
+        None => {
+            fs::create_dir_all(&settings.backup_dir)?;
+            let name = format!("better-one-tab-{}.tar.zst", chrono::Utc::now().format("%Y%m%dT%H%M%SZ"));
+            Path::new(&settings.backup_dir).join(name)
+        }
+    };
+    let report = create(Path::new(&settings.data_dir), &config_files(), &archive)?;
+    audit::record("backup_created", "", serde_json::json!({
+        "archive": report.archive,
+        "files": report.files,
+        "bytes": report.bytes,
+    }));
+    Ok(report)
+}
+
+/// Runs the backup on a blocking thread, for the admin endpoint.
+pub async fn run_online() -> Result<BackupReport, String> {
+    match tokio::task::spawn_blocking(move || run(None)).await {
+        Ok(value) => value.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Checks an archive without touching the data directory.
+pub fn verify(archive: &Path) -> Result<BackupManifest, Error> {
+    let target = std::env::temp_dir().join(format!("bot-verify-{}", generate_random_string(8)));
+    let result = extract(archive, &target);
+    let _ = fs::remove_dir_all(&target);
+    result
+}
+
+/// Names in `dir` except dotfiles, which are temp files and earlier backups and restores.
+fn entries(dir: &Path) -> Result<Vec<String>, Error> {
+    Ok(fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| !name.starts_with('.'))
+        .collect())
+}
+
+/// Moves the entries of `from` into `to`, returns the moved names. On error the entries moved
+/// so far are moved back.
+fn move_entries(from: &Path, to: &Path) -> Result<Vec<String>, Error> {
+    let mut moved = Vec::new();
+    for name in entries(from)? {
+        if let Err(e) = fs::rename(from.join(&name), to.join(&name)) {
+            move_back(from, to, &moved);
+            return Err(e);
+        }
+        moved.push(name);
+    }
+    Ok(moved)
+}
+
+/// Undoes `move_entries(from, to)` for `names`.
+fn move_back(from: &Path, to: &Path, names: &[String]) {
+    for name in names.iter().rev() {
+        if let Err(e) = fs::rename(to.join(name), from.join(name)) {
+            error!("Failed to move {} back to {}: {}", to.join(name).display(), from.display(), e);
+        }
+    }
+}
+
+/// Moves the contents of `data_dir` to `previous` and those of `restored` to `data_dir`, all or
+/// nothing. Returns the moved names of both.
+fn swap_data(data_dir: &Path, restored: &Path, previous: &Path) -> Result<(Vec<String>, Vec<String>), Error> {
+    fs::create_dir(previous)?;
+    let previous_names = move_entries(data_dir, previous)?;
+    match move_entries(restored, data_dir) {
+        Ok(restored_names) => Ok((previous_names, restored_names)),
+        Err(e) => {
+            move_back(data_dir, previous, &previous_names);
+            Err(e)
+        }
+    }
+}
+
+/// Where an archived config file goes: log4rs.yaml into the config directory, the settings file
+/// to `--config` when the server is started with it, otherwise into the config directory.
+fn config_target(name: &str) -> Result<PathBuf, Error> {
+    let source = config_source();
+    let config_file = match &source.config_file {
+        Some(value) if name != "log4rs.yaml" => PathBuf::from(value),
+        _ => return Ok(source.config_dir().join(name)),
+    };
+    // the format follows the extension
+    if config_file.extension() != Path::new(name).extension() {
+        return Err(invalid(format!("Archived settings file {} does not match the format of {}", name, config_file.display())));
+    }
+    Ok(config_file)
+}
+
+fn restore_config(extracted: &Path) -> Result<Vec<String>, Error> {
+    let mut config = Vec::new();
+    for name in entries(extracted)? {
+        let path = config_target(&name)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_atomic(&path, &fs::read(extracted.join(&name))?)?;
+        config.push(path.to_string_lossy().into_owned());
+    }
+    Ok(config)
+}
+
+/// Replaces the data directory, and with `with_config` the settings files, by a verified archive.
+/// The previous contents are moved to `<data_dir>/.before-restore-<time>`, and moved back when
+/// the restore fails halfway. The server must be stopped.
+pub fn restore(archive: &Path, with_config: bool) -> Result<RestoreReport, Error> {
+    let data_dir = PathBuf::from(&CONFIG_INSTANCE.load().settings.data_dir);
+    fs::create_dir_all(&data_dir)?;
+    let now = chrono::Utc::now().timestamp();
+    // inside the data directory, so moving works when it is a mount point
+    let extracted = data_dir.join(format!(".restore-{}", now));
+    let manifest = match extract(archive, &extracted) {
+        Ok(value) => value,
+        Err(e) => {
+            let _ = fs::remove_dir_all(&extracted);
+            return Err(e);
+        }
+    };
+
+    let previous = data_dir.join(format!(".before-restore-{}", now));
+    let result = swap_data(&data_dir, &extracted.join("data"), &previous).and_then(|(previous_names, restored_names)| {
+        if !with_config || !extracted.join("config").exists() {
+            return Ok(Vec::new());
+        }
+        restore_config(&extracted.join("config")).inspect_err(|_| {
+            move_back(&extracted.join("data"), &data_dir, &restored_names);
+            move_back(&data_dir, &previous, &previous_names);
+        })
+    });
+    // kept on error, a rollback that failed leaves restored files there
+    let config = result?;
+    // the data is replaced already, a leftover directory is no reason to report a failure
+    if let Err(e) = fs::remove_dir_all(&extracted) {
+        error!("Failed to remove {}: {}", extracted.display(), e);
+    }
+
+    let report = RestoreReport {
+        archive: archive.to_string_lossy().into_owned(),
+        created_at: manifest.created_at,
+        files: manifest.files.len(),
+        previous: previous.to_string_lossy().into_owned(),
+        config,
+    };
+    audit::record("backup_restored", "", serde_json::json!({
+        "archive": report.archive,
+        "created_at": report.created_at,
+        "previous": report.previous,
+    }));
+    Ok(report)
+}
+
+// test module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABS: &str = r#"[{"_id":"1","uuid":"g1","color":"","expand":true,"pinned":false,"tabs":[
+        {"uuid":"t1","favIconUrl":"","pinned":false,"title":"a","url":"https://a.example.com"}],
+        "tags":[],"time":0,"title":"group","titleEditing":null,"updatedAt":0}]"#;
+
+    fn data_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bot-backup-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("history").join("100")).unwrap();
+        fs::write(dir.join("users.txt"), "alice,secret\n").unwrap();
+        fs::write(dir.join("alice.txt"), "token").unwrap();
+        fs::write(dir.join("alice.json"), TABS).unwrap();
+        fs::write(dir.join(".alice.json.abc.tmp"), "[").unwrap();
+        history::write_snapshot(&dir.join("history"), 100, "alice.json", TABS).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_create_and_extract() {
+        let dir = data_dir("create");
+        fs::create_dir(dir.join("quarantine")).unwrap();
+        let settings = dir.with_extension("json");
+        fs::write(&settings, "{}").unwrap();
+        let archive = dir.with_extension("tar.zst");
+        let report = create(&dir, &[(String::from("appsettings.json"), settings.clone())], &archive).unwrap();
+        assert!(fs::read_dir(&dir).unwrap().all(|entry| !entry.unwrap().file_name().to_string_lossy().starts_with(".backup")));
+
+        let target = dir.with_extension("extracted");
+        let manifest = extract(&archive, &target).unwrap();
+        assert_eq!(report.files, manifest.files.len());
+        let paths = manifest.files.iter().map(|file| file.path.as_str()).collect::<Vec<&str>>();
+        assert!(paths.contains(&"data/alice.json") && paths.contains(&"data/history/100/alice.json.manifest") && paths.contains(&"config/appsettings.json"));
+        assert!(!paths.iter().any(|path| path.contains(".tmp")));
+        assert_eq!(manifest.dirs, vec!["data/quarantine"]);
+        assert!(target.join("data").join("quarantine").is_dir());
+        assert_eq!(fs::read_to_string(target.join("data").join("alice.json")).unwrap(), TABS);
+        assert_eq!(history::read_snapshot(&target.join("data").join("history"), 100, "alice.json").unwrap(), TABS);
+        for path in [dir, target] {
+            fs::remove_dir_all(path).unwrap();
+        }
+        for path in [archive, settings] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_extract_rejects_changed_and_corrupt_files() {
+        let dir = data_dir("reject");
+        let archive = dir.with_extension("tar.zst");
+        let target = dir.with_extension("extracted");
+        create(&dir, &[], &archive).unwrap();
+        let mut manifest = extract(&archive, &target).unwrap();
+
+        // rebuild the archive from the extracted files with one of them changed
+        fs::write(target.join("data").join("alice.txt"), "other").unwrap();
+        write_archive(&target, &manifest, &archive).unwrap();
+        let error = extract(&archive, &dir.with_extension("changed")).unwrap_err();
+        assert_eq!(error.to_string(), "data/alice.txt does not match its checksum");
+
+        // a checksum that matches a half written tabs file
+        fs::write(target.join("data").join("alice.txt"), "token").unwrap();
+        fs::write(target.join("data").join("alice.json"), "[{").unwrap();
+        let entry = manifest.files.iter_mut().find(|file| file.path == "data/alice.json").unwrap();
+        (entry.size, entry.sha256) = checksum(&target.join("data").join("alice.json")).unwrap();
+        write_archive(&target, &manifest, &archive).unwrap();
+        let error = extract(&archive, &dir.with_extension("corrupt")).unwrap_err();
+        assert!(error.to_string().starts_with("Corrupt files: alice.json"), "{}", error);
+        for extension in ["", "extracted", "changed", "corrupt"] {
+            fs::remove_dir_all(dir.with_extension(extension)).unwrap();
+        }
+        fs::remove_file(archive).unwrap();
+    }
+
+    #[test]
+    fn test_move_entries_rolls_back() {
+        let dir = std::env::temp_dir().join(format!("bot-backup-move-{}", std::process::id()));
+        let (from, to) = (dir.join("from"), dir.join("to"));
+        for path in [from.join("a"), from.join("b").join("c"), from.join("d"), to.join("b").join("e")] {
+            fs::create_dir_all(path).unwrap();
+        }
+        // b can not replace the non-empty b in to
+        assert!(move_entries(&from, &to).is_err());
+        let mut names = entries(&from).unwrap();
+        names.sort();
+        assert_eq!(names, vec!["a", "b", "d"]);
+        assert_eq!(entries(&to).unwrap(), vec!["b"]);
+
+        fs::remove_dir_all(to.join("b")).unwrap();
+        assert_eq!(move_entries(&from, &to).unwrap().len(), 3);
+        assert!(entries(&from).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use axum::http::StatusCode;
+use log::{error, info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+lazy_static! {
+    pub static ref BANS: Mutex<Bans> = Mutex::new(Bans::load(&crate::util::data_path("bans.json").to_string_lossy()));
+}
+
+fn default_ban_max_failures() -> u32 {
+    10
+}
+
+fn default_ban_find_time() -> i64 {
+    10 * 60
+}
+
+fn default_ban_time() -> i64 {
+    60 * 60
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BanSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    // failures within find_time that trigger a ban
+    #[serde(default = "default_ban_max_failures")]
+    #[schemars(range(min = 1))]
+    pub max_failures: u32,
+    // by seconds
+    #[serde(default = "default_ban_find_time")]
+    #[schemars(range(min = 1))]
+    pub find_time: i64,
+    // by seconds, how long a ban lasts
+    #[serde(default = "default_ban_time")]
+    #[schemars(range(min = 1))]
+    pub ban_time: i64,
+}
+
+impl Default for BanSettings {
+    fn default() -> Self {
+        BanSettings {
+            enabled: false,
+            max_failures: default_ban_max_failures(),
+            find_time: default_ban_find_time(),
+            ban_time: default_ban_time(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Ban {
+    pub ip: IpAddr,
+    pub reason: String,
+    pub created_at: i64,
+    pub until: i64,
+}
+
+/// Error responses counted as failures: bad credentials or tokens, region blocks and malformed requests.
+pub fn is_failure_status(status: StatusCode) -> bool {
+    matches!(status, StatusCode::BAD_REQUEST
+        | StatusCode::UNAUTHORIZED
+        | StatusCode::FORBIDDEN
+        | StatusCode::UNSUPPORTED_MEDIA_TYPE
+        | StatusCode::UNPROCESSABLE_ENTITY)
+}
+
+/// Failure counters per ip and the active bans, bans are persisted to `filename`.
+pub struct Bans {
+    filename: Option<String>,
+    failures: HashMap<IpAddr, Vec<i64>>,
+    bans: HashMap<IpAddr, Ban>,
+    // last time ips without recent failures were dropped
+    swept_at: i64,
+}
+
+impl Bans {
+    pub fn new() -> Self {
+        Bans {
+            filename: None,
+            failures: HashMap::new(),
+            bans: HashMap::new(),
+            swept_at: 0,
+        }
+    }
+
+    pub fn load(filename: &str) -> Self {
+        let mut bans = Bans::new();
+        bans.filename = Some(filename.to_string());
+        if let Ok(contents) = fs::read_to_string(filename) {
+            match serde_json::from_str::<Vec<Ban>>(&contents) {
+                Ok(value) => {
+                    let now = chrono::Utc::now().timestamp();
+                    bans.bans = value.into_iter()
+                        .filter(|ban| ban.until > now)
+                        .map(|ban| (ban.ip, ban))
+                        .collect();
+                    info!("Loaded {} active bans from {}", bans.bans.len(), filename);
+                }
+                Err(e) => error!("Failed to parse {}: {}", filename, e),
+            }
+        }
+        bans
+    }
+
+    fn save(&self) {
+        let filename = match &self.filename {
+            Some(value) => value,
+            None => return,
+        };
+        let contents = serde_json::to_string_pretty(&self.bans.values().collect::<Vec<&Ban>>()).unwrap();
+        if let Err(e) = crate::util::write_atomic(Path::new(filename), contents.as_bytes()) {
+            error!("Failed to save bans to {}: {}", filename, e);
+        }
+    }
+
+    /// The active ban of the ip, expired bans are dropped.
+    pub fn get_ban(&mut self, ip: IpAddr, now: i64) -> Option<Ban> {
+        match self.bans.get(&ip) {
+            Some(ban) if ban.until > now => Some(ban.clone()),
+            Some(_) => {
+                self.bans.remove(&ip);
+                self.save();
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Counts a failure, returns the new ban when the ip reached `max_failures` within `find_time`.
+    pub fn record_failure(&mut self, ip: IpAddr, reason: &str, now: i64, settings: &BanSettings) -> Option<Ban> {
+        self.sweep(now, settings);
+        let failures = self.failures.entry(ip).or_default();
+        failures.retain(|time| now - time < settings.find_time);
+        failures.push(now);
+        if (failures.len() as u32) < settings.max_failures {
+            return None;
+        }
+        self.failures.remove(&ip);
+        let ban = Ban {
+            ip,
+            reason: format!("{} failures in {} seconds, last: {}", settings.max_failures, settings.find_time, reason),
+            created_at: now,
+            until: now + settings.ban_time,
+        };
+        warn!("Banned {} until {}: {}", ip, ban.until, ban.reason);
+        self.bans.insert(ip, ban.clone());
+        self.save();
+        Some(ban)
+    }
+
+    /// Drops ips without failures within `find_time` and expired bans, at most once per `find_time`,
+    /// so rotating addresses do not grow the maps.
+    fn sweep(&mut self, now: i64, settings: &BanSettings) {
+        if now - self.swept_at < settings.find_time {
+            return;
+        }
+        self.swept_at = now;
+        self.failures.retain(|_, failures| failures.last().is_some_and(|time| now - time < settings.find_time));
+        let count = self.bans.len();
+        self.bans.retain(|_, ban| ban.until > now);
+        if self.bans.len() != count {
+            self.save();
+        }
+    }
+
+    pub fn unban(&mut self, ip: IpAddr) -> bool {
+        self.failures.remove(&ip);
+        let removed = self.bans.remove(&ip).is_some();
+        if removed {
+            info!("Unbanned {}", ip);
+            self.save();
+        }
+        removed
+    }
+
+    pub fn list(&self, now: i64) -> Vec<Ban> {
+        let mut bans: Vec<Ban> = self.bans.values().filter(|ban| ban.until > now).cloned().collect();
+        bans.sort_by_key(|ban| ban.created_at);
+        bans
+    }
+}
+
+// test module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> BanSettings {
+        BanSettings { enabled: true, max_failures: 3, find_time: 60, ban_time: 600 }
+    }
+
+    fn ip() -> IpAddr {
+        "203.0.113.7".parse().unwrap()
+    }
+
+    #[test]
+    fn test_ban_after_max_failures() {
+        let mut bans = Bans::new();
+        assert!(bans.record_failure(ip(), "401", 1000, &settings()).is_none());
+        assert!(bans.record_failure(ip(), "401", 1001, &settings()).is_none());
+        let ban = bans.record_failure(ip(), "403", 1002, &settings()).unwrap();
+        assert_eq!(ban.until, 1602);
+        assert!(bans.get_ban(ip(), 1601).is_some());
+        assert!(bans.get_ban(ip(), 1602).is_none());
+        assert!(bans.list(1602).is_empty());
+    }
+
+    #[test]
+    fn test_failures_outside_find_time() {
+        let mut bans = Bans::new();
+        bans.record_failure(ip(), "401", 1000, &settings());
+        bans.record_failure(ip(), "401", 1030, &settings());
+        assert!(bans.record_failure(ip(), "401", 1070, &settings()).is_none());
+        assert!(bans.record_failure(ip(), "401", 1080, &settings()).is_some());
+    }
+
+    #[test]
+    fn test_sweep_old_failures() {
+        let mut bans = Bans::new();
+        for i in 0..100u8 {
+            bans.record_failure(IpAddr::from([10, 0, 0, i]), "401", 1000, &settings());
+        }
+        assert_eq!(bans.failures.len(), 100);
+        bans.record_failure(ip(), "401", 1030, &settings());
+        assert_eq!(bans.failures.len(), 101);
+        bans.record_failure(ip(), "401", 1070, &settings());
+        assert_eq!(bans.failures.len(), 1);
+    }
+
+    #[test]
+    fn test_unban() {
+        let mut bans = Bans::new();
+        for time in 0..3 {
+            bans.record_failure(ip(), "401", 1000 + time, &settings());
+        }
+        assert_eq!(bans.list(1010).len(), 1);
+        assert!(bans.unban(ip()));
+        assert!(!bans.unban(ip()));
+        assert!(bans.get_ban(ip(), 1010).is_none());
+    }
+
+    #[test]
+    fn test_bans_persist() {
+        let filename = std::env::temp_dir().join(format!("test-bans-{}.json", std::process::id()));
+        let filename = filename.to_str().unwrap();
+        let now = chrono::Utc::now().timestamp();
+        let mut bans = Bans::load(filename);
+        for time in 0..3 {
+            bans.record_failure(ip(), "401", now + time, &settings());
+        }
+        let loaded = Bans::load(filename);
+        fs::remove_file(filename).unwrap();
+        assert_eq!(loaded.list(now).len(), 1);
+        assert_eq!(loaded.list(now)[0].ip, ip());
+    }
+}
@@ -0,0 +1,342 @@
+use std::io::IsTerminal;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use crate::config::{settings_schema, ConfigSource, CONFIG_INSTANCE};
+use crate::ip::{IpLists, Ips, Reloadable};
+use crate::policy::{PolicyInput, ROUTE_GROUPS};
+use crate::labels::{is_valid_label, LABELS};
+use crate::backup;
+use crate::fsck;
+use crate::history;
+use crate::oidc::is_valid_username;
+use crate::retention;
+use crate::util::{add_user, generate_random_string, is_valid_password, list_history, read_history, read_lines_from_file, remove_user, remove_user_token, restore_history, set_user_password, data_path};
+
+/// Sync server of Better OneTab.
+///
+/// Settings are layered: defaults, the settings file, `BOT_*` environment variables
+/// (e.g. `BOT_ROTATE_COUNT=10`, `BOT_OIDC__CLIENT_SECRET=...`) and these flags.
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// port to listen on, replaces the port of the bind address
+    pub port: Option<u16>,
+    /// settings file, json, toml or yaml, instead of searching the config directory
+    #[arg(long, global = true, env = "BOT_CONFIG")]
+    pub config: Option<String>,
+    /// directory searched for appsettings.json, .toml or .yaml [default: ./config]
+    #[arg(long, global = true, env = "BOT_CONFIG_DIR")]
+    pub config_dir: Option<String>,
+    /// directory of users.txt, tokens, tabs and history [default: ./data]
+    #[arg(long, global = true)]
+    pub data_dir: Option<String>,
+    /// directory of the log files [default: ./logs]
+    #[arg(long, global = true)]
+    pub log_dir: Option<String>,
+    /// address to listen on [default: 0.0.0.0:3000]
+    #[arg(long, global = true)]
+    pub bind: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// run the web server, the default without a command
+    Serve {
+        /// port to listen on, replaces the port of the bind address
+        port: Option<u16>,
+    },
+    /// manage users.txt
+    #[command(subcommand)]
+    User(UserCommand),
+    /// manage login tokens
+    #[command(subcommand)]
+    Token(TokenCommand),
+    /// manage tabs history snapshots
+    #[command(subcommand)]
+    History(HistoryCommand),
+    /// back up and restore the data directory and settings
+    #[command(subcommand)]
+    Backup(BackupCommand),
+    /// check the tabs files, history and tokens in the data directory
+    Fsck {
+        /// repair what can be repaired, e.g. remove orphans and quarantine corrupt snapshots
+        /// (stop the server first, or use POST /api/admin/fsck?repair=true while serving)
+        #[arg(long)]
+        repair: bool,
+    },
+    /// load and validate the settings, then print them
+    CheckConfig,
+    /// print the JSON Schema of appsettings.json
+    Schema,
+    /// look up ips in the GeoIP, ASN and ip list tables
+    #[command(subcommand)]
+    Geoip(GeoipCommand),
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// add a user, a random password is generated and printed unless --read-password is given
+    Add {
+        username: String,
+        /// ask for the password, or read it from stdin when that is not a terminal
+        #[arg(long)]
+        read_password: bool,
+    },
+    /// remove a user and their token, tabs and history are kept
+    Remove { username: String },
+    /// change the password of a user, a random one is generated and printed unless --read-password is given
+    Passwd {
+        username: String,
+        /// ask for the password, or read it from stdin when that is not a terminal
+        #[arg(long)]
+        read_password: bool,
+    },
+    /// list users and their region locks
+    List,
+}
+
+#[derive(Subcommand)]
+pub enum TokenCommand {
+    /// log a user out everywhere
+    Revoke { username: String },
+}
+
+#[derive(Subcommand)]
+pub enum HistoryCommand {
+    /// list snapshots, of one user or all
+    List { username: Option<String> },
+    /// make a snapshot the current tabs of a user, the current tabs are kept as a snapshot
+    Restore { username: String, timestamp: i64 },
+    /// label a snapshot, labeled snapshots are never pruned. Without a label the label is removed
+    Label { username: String, timestamp: i64, label: Option<String> },
+    /// remove old snapshots according to the retention settings
+    Prune {
+        /// only report what each rule would remove
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// move plain snapshot copies into the compressed object store
+    Compact,
+}
+
+#[derive(Subcommand)]
+pub enum BackupCommand {
+    /// write a tar.zst archive of the data directory and settings, the server may keep running
+    Create {
+        /// archive path, default backup_dir/better-one-tab-<time>.tar.zst
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// check the checksums and the tabs files of an archive
+    Verify { archive: PathBuf },
+    /// replace the data directory with a verified archive, stop the server first
+    Restore {
+        archive: PathBuf,
+        /// also restore the settings files into the config directory, the settings file to --config when given
+        #[arg(long)]
+        with_config: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum GeoipCommand {
+    /// print region, ASN, ip lists and the policy decision of every route group
+    Lookup { ip: IpAddr },
+}
+
+impl Cli {
+    pub fn config_source(&self) -> ConfigSource {
+        let mut overrides = serde_json::Map::new();
+        for (key, value) in [("data_dir", &self.data_dir), ("log_dir", &self.log_dir), ("bind", &self.bind)] {
+            if let Some(value) = value {
+                overrides.insert(key.to_string(), serde_json::Value::String(value.clone()));
+            }
+        }
+        let port = match &self.command {
+            Some(Command::Serve { port: Some(port) }) => Some(*port),
+            _ => self.port,
+        };
+        ConfigSource {
+            config_dir: self.config_dir.clone(),
+            config_file: self.config.clone(),
+            overrides,
+            port,
+        }
+    }
+}
+
+/// Passwords are not taken as arguments, those end up in the shell history and process list.
+fn read_password() -> Result<String, String> {
+    if !std::io::stdin().is_terminal() {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line).map_err(|e| format!("Error reading password: {}", e))?;
+        return Ok(line.trim_end_matches(['\r', '\n']).to_string());
+    }
+    let password = rpassword::prompt_password("Password: ").map_err(|e| format!("Error reading password: {}", e))?;
+    if rpassword::prompt_password("Repeat password: ").map_err(|e| format!("Error reading password: {}", e))? != password {
+        return Err("Passwords do not match".to_string());
+    }
+    Ok(password)
+}
+
+fn password_or_random(read: bool) -> Result<String, String> {
+    if !read {
+        let password = generate_random_string(16);
+        println!("Password: {}", password);
+        return Ok(password);
+    }
+    let password = read_password()?;
+    if !is_valid_password(&password) {
+        return Err("Password must not be empty or contain commas or line breaks".to_string());
+    }
+    Ok(password)
+}
+
+/// Usernames become file names in the data directory.
+fn check_username(username: &str) -> Result<(), String> {
+    if !is_valid_username(username) {
+        return Err(format!("Invalid username: {}", username));
+    }
+    Ok(())
+}
+
+/// Runs an admin command against the loaded config, everything except `serve`.
+pub fn run(command: Command) -> Result<(), String> {
+    let settings = &CONFIG_INSTANCE.load().settings;
+    match command {
+        Command::Serve { .. } => unreachable!("serve is handled by main"),
+        Command::User(UserCommand::Add { username, read_password }) => {
+            check_username(&username)?;
+            let password = password_or_random(read_password)?;
+            add_user(&username, &password)?;
+            println!("Added user {}", username);
+        }
+        Command::User(UserCommand::Remove { username }) => {
+            check_username(&username)?;
+            match remove_user(&username) {
+                Ok(true) => println!("Removed user {}", username),
+                Ok(false) => return Err(format!("No such user: {}", username)),
+                Err(e) => return Err(format!("Error saving file users.txt: {}", e)),
+            }
+        }
+        Command::User(UserCommand::Passwd { username, read_password }) => {
+            check_username(&username)?;
+            let password = password_or_random(read_password)?;
+            match set_user_password(&username, &password) {
+                Ok(true) => println!("Changed password of {}", username),
+                Ok(false) => return Err(format!("No such user: {}", username)),
+                Err(e) => return Err(format!("Error saving file users.txt: {}", e)),
+            }
+        }
+        Command::User(UserCommand::List) => {
+            let users = read_lines_from_file("users.txt").map_err(|e| format!("Error reading file users.txt: {}", e))?;
+            for user in users {
+                if user.region_lock.is_empty() {
+                    println!("{}", user.username);
+                } else {
+                    println!("{} (region lock: {})", user.username, user.region_lock.join(", "));
+                }
+            }
+        }
+        Command::Token(TokenCommand::Revoke { username }) => {
+            check_username(&username)?;
+            remove_user_token(&username).map_err(|e| format!("No token of {}: {}", username, e))?;
+            println!("Revoked token of {}", username);
+        }
+        Command::History(HistoryCommand::List { username }) => {
+            if let Some(username) = &username {
+                check_username(username)?;
+            }
+            let filename = username.map(|username| format!("{}.json", username));
+            let snapshots = list_history(filename.as_deref()).map_err(|e| format!("Error reading history: {}", e))?;
+            for snapshot in snapshots {
+                let time = chrono::DateTime::from_timestamp(snapshot.timestamp, 0).map(|time| time.to_rfc3339()).unwrap_or_default();
+                let counts = snapshot.meta
+                    .map(|meta| format!("  {} groups, {} tabs", meta.group_count, meta.tab_count))
+                    .unwrap_or_default();
+                match snapshot.label {
+                    Some(label) => println!("{}  {}  {}{}  [{}]", snapshot.timestamp, time, snapshot.filename, counts, label),
+                    None => println!("{}  {}  {}{}", snapshot.timestamp, time, snapshot.filename, counts),
+                }
+            }
+        }
+        Command::History(HistoryCommand::Label { username, timestamp, label }) => {
+            check_username(&username)?;
+            if label.as_deref().is_some_and(|label| !is_valid_label(label)) {
+                return Err("Label must not be empty, longer than 100 characters or contain line breaks".to_string());
+            }
+            let filename = format!("{}.json", username);
+            match read_history(&filename, timestamp) {
+                Ok(Some(_)) => {}
+                Ok(None) => return Err(format!("No snapshot {} of {}", timestamp, username)),
+                Err(e) => return Err(format!("Error reading snapshot {} of {}: {}", timestamp, username, e)),
+            }
+            LABELS.lock().unwrap().set(&filename, timestamp, label.clone())
+                .map_err(|e| format!("Error saving labels: {}", e))?;
+            match label {
+                Some(label) => println!("Labeled snapshot {} of {}: {}", timestamp, username, label),
+                None => println!("Removed label of snapshot {} of {}", timestamp, username),
+            }
+        }
+        Command::History(HistoryCommand::Restore { username, timestamp }) => {
+            check_username(&username)?;
+            restore_history(&format!("{}.json", username), timestamp)
+                .map_err(|e| format!("Error restoring snapshot {} of {}: {}", timestamp, username, e))?;
+            println!("Restored snapshot {} of {}", timestamp, username);
+        }
+        Command::History(HistoryCommand::Prune { dry_run }) => {
+            let report = retention::run(dry_run).map_err(|e| format!("Error pruning history: {}", e))?;
+            println!("{}", report);
+        }
+        Command::History(HistoryCommand::Compact) => {
+            let converted = history::compact(&data_path("history")).map_err(|e| format!("Error compacting history: {}", e))?;
+            println!("Compacted {} snapshots", converted);
+        }
+        Command::Backup(BackupCommand::Create { output }) => {
+            let report = backup::run(output).map_err(|e| format!("Error writing backup: {}", e))?;
+            println!("{}", report);
+        }
+        Command::Backup(BackupCommand::Verify { archive }) => {
+            let manifest = backup::verify(&archive).map_err(|e| format!("Invalid backup {}: {}", archive.display(), e))?;
+            println!("Backup from {} is valid: {} files, {} bytes", manifest.created_at, manifest.files.len(), manifest.bytes());
+        }
+        Command::Backup(BackupCommand::Restore { archive, with_config }) => {
+            let report = backup::restore(&archive, with_config).map_err(|e| format!("Error restoring backup {}: {}", archive.display(), e))?;
+            println!("{}", report);
+        }
+        Command::Fsck { repair } => {
+            let report = fsck::run(repair).map_err(|e| format!("Error checking data: {}", e))?;
+            println!("{}", report);
+            if report.unrepaired() > 0 {
+                return Err(format!("{} issues are not repaired", report.unrepaired()));
+            }
+        }
+        Command::CheckConfig => {
+            // main already failed on an invalid config
+            println!("{}", settings);
+            println!("Config is valid");
+        }
+        Command::Schema => println!("{}", settings_schema()),
+        Command::Geoip(GeoipCommand::Lookup { ip }) => {
+            let ips = Ips::load(&settings.geoip_files);
+            if ips.validate().is_err() {
+                println!("Warning: GeoIP table is empty or invalid");
+            }
+            let region = ips.get_region(ip).to_string();
+            let asn = Ips::load(&settings.asn_files).get_asn(ip);
+            let lists = IpLists::load(&settings.ip_lists).lists_containing(ip);
+            println!("ip: {}", ip);
+            println!("region: {}", region);
+            println!("asn: {}", asn.map(|asn| format!("AS{}", asn)).unwrap_or(String::from("unknown")));
+            println!("ip lists: {:?}", lists);
+            for route_group in ROUTE_GROUPS {
+                let decision = settings.route_policy(route_group).evaluate(&PolicyInput { ip, region: &region, asn, lists: &lists });
+                println!("{}: {}", route_group, decision);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use std::net::{IpAddr, SocketAddr};
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+use crate::config::ForwardedHeader;
+use crate::ip::canonical;
+
+/// Client address and region resolved by the ip filter middleware, stored in the request extensions.
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+    // None when the forwarding chain could not be parsed
+    pub ip: Option<IpAddr>,
+    pub region: String,
+}
+
+fn is_trusted(ip: IpAddr, trusted_proxies: &[IpNet]) -> bool {
+    trusted_proxies.iter().any(|net| net.contains(&ip))
+}
+
+/// Parses one hop, which may carry a port and quotes: `1.2.3.4`, `"1.2.3.4:80"`, `"[2001:db8::1]:4711"`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        let end = rest.find(']')?;
+        return rest[..end].parse::<IpAddr>().ok();
+    }
+    node.parse::<IpAddr>().ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+fn header_values(headers: &HeaderMap, name: &str) -> Vec<String> {
+    headers.get_all(name).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+/// `for=` of every RFC 7239 element, elements without one are kept as empty hops
+fn forwarded_hops(headers: &HeaderMap) -> Vec<String> {
+    header_values(headers, "forwarded").iter()
+        .map(|element| {
+            element.split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .map(|(_, value)| value.trim().to_string())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Resolves the client address. Starting from the socket peer, the forwarding chain is walked
+/// right to left while the current hop is a trusted proxy, the first untrusted hop is the client.
+/// Only `header` is read, a proxy passes the other one through as the client sent it.
+/// A hop that can not be parsed is returned as error.
+pub fn resolve_client_ip(socket_ip: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet], header: ForwardedHeader) -> Result<IpAddr, String> {
+    let mut client_ip = canonical(socket_ip);
+    if !is_trusted(client_ip, trusted_proxies) {
+        return Ok(client_ip);
+    }
+
+    let hops = match header {
+        ForwardedHeader::XForwardedFor => header_values(headers, "x-forwarded-for"),
+        ForwardedHeader::Forwarded => forwarded_hops(headers),
+    };
+    for hop in hops.iter().rev() {
+        client_ip = match parse_node(hop) {
+            Some(value) => canonical(value),
+            None => return Err(hop.clone()),
+        };
+        if !is_trusted(client_ip, trusted_proxies) {
+            return Ok(client_ip);
+        }
+    }
+    Ok(client_ip)
+}
+
+// test module
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use super::*;
+
+    fn trusted() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()]
+    }
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn test_untrusted_peer_ignores_headers() {
+        let headers = headers("x-forwarded-for", "203.0.113.7");
+        let ip = resolve_client_ip("198.51.100.1".parse().unwrap(), &headers, &trusted(), ForwardedHeader::XForwardedFor);
+        assert_eq!(ip, Ok("198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_x_forwarded_for_chain() {
+        // the client spoofs the first entry, the trusted proxies append the real hops
+        let headers = headers("x-forwarded-for", "1.1.1.1, 203.0.113.7, 10.0.0.2");
+        let ip = resolve_client_ip("10.0.0.1".parse().unwrap(), &headers, &trusted(), ForwardedHeader::XForwardedFor);
+        assert_eq!(ip, Ok("203.0.113.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_forwarded_header() {
+        let mut headers = headers("forwarded", "for=1.1.1.1, for=\"[2001:db8::7]:4711\";proto=https");
+        headers.insert("x-forwarded-for", HeaderValue::from_static("198.51.100.9"));
+        let ip = resolve_client_ip("fd00::1".parse().unwrap(), &headers, &trusted(), ForwardedHeader::Forwarded);
+        assert_eq!(ip, Ok("2001:db8::7".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_other_header_is_ignored() {
+        // a client sent its own Forwarded, the proxy only appended X-Forwarded-For
+        let mut spoofed = headers("forwarded", "for=1.1.1.1");
+        spoofed.insert("x-forwarded-for", HeaderValue::from_static("203.0.113.7"));
+        let ip = resolve_client_ip("10.0.0.1".parse().unwrap(), &spoofed, &trusted(), ForwardedHeader::XForwardedFor);
+        assert_eq!(ip, Ok("203.0.113.7".parse().unwrap()));
+
+        let spoofed = headers("x-forwarded-for", "1.1.1.1");
+        let ip = resolve_client_ip("10.0.0.1".parse().unwrap(), &spoofed, &trusted(), ForwardedHeader::Forwarded);
+        assert_eq!(ip, Ok("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_all_hops_trusted() {
+        let headers = headers("x-forwarded-for", "10.1.1.1:8080, 10.0.0.2");
+        let ip = resolve_client_ip("10.0.0.1".parse().unwrap(), &headers, &trusted(), ForwardedHeader::XForwardedFor);
+        assert_eq!(ip, Ok("10.1.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_ipv4_mapped_peer() {
+        let headers = headers("x-forwarded-for", "203.0.113.7");
+        let ip = resolve_client_ip("::ffff:10.0.0.1".parse().unwrap(), &headers, &trusted(), ForwardedHeader::XForwardedFor);
+        assert_eq!(ip, Ok("203.0.113.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_invalid_hop() {
+        let headers = headers("forwarded", "for=unknown");
+        let ip = resolve_client_ip("10.0.0.1".parse().unwrap(), &headers, &trusted(), ForwardedHeader::Forwarded);
+        assert_eq!(ip, Err("unknown".to_string()));
+    }
+}
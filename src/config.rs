@@ -1,9 +1,19 @@
+use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
 use std::{fmt, fs};
-use std::sync::Mutex;
-use log::{info, warn};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use ipnet::IpNet;
+use std::sync::Arc;
+use arc_swap::ArcSwap;
+use log::{error, info, warn};
+use crate::ban::BanSettings;
+use crate::policy::{region_code_violations, RegionPolicy, ROUTE_GROUPS};
+use crate::retention::RetentionSettings;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[derive(PartialEq)]
 pub enum RotateType {
     // delete old history files by the total files stored
@@ -15,6 +25,9 @@ pub enum RotateType {
     // by MB, delete old history files by the total size of the history directory
     #[serde(rename = "total_size")]
     TotalSize,
+    // keep every snapshot of the last day, one per hour for a week, one per day for a month, one per week for a year
+    #[serde(rename = "tiered")]
+    Tiered,
     #[serde(rename = "reserved")]
     Reserved,
 }
@@ -25,6 +38,7 @@ impl Clone for RotateType {
             RotateType::HistoryCount => RotateType::HistoryCount,
             RotateType::StoredTime => RotateType::StoredTime,
             RotateType::TotalSize => RotateType::TotalSize,
+            RotateType::Tiered => RotateType::Tiered,
             RotateType::Reserved => RotateType::Reserved,
         }
     }
@@ -36,19 +50,216 @@ impl fmt::Display for RotateType {
             RotateType::HistoryCount => write!(f, "Rotate history tag files by total count"),
             RotateType::StoredTime => write!(f, "Rotate history tag files by its stored time"),
             RotateType::TotalSize => write!(f, "Rotate history tag files by total size of history directory"),
+            RotateType::Tiered => write!(f, "Rotate history tag files by tiers, hourly for a week, daily for a month, weekly for a year"),
             RotateType::Reserved => write!(f, "Reserved field"),
         }
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+pub enum PolicyAction {
+    #[serde(rename = "allow")]
+    Allow,
+    #[serde(rename = "deny")]
+    Deny,
+}
+
+impl fmt::Display for PolicyAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PolicyAction::Allow => write!(f, "allow"),
+            PolicyAction::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+pub enum HistoryBackend {
+    // snapshot directories with the compressed object store
+    #[serde(rename = "files")]
+    Files,
+    // a git repository per user, every sync is a commit
+    #[serde(rename = "git")]
+    Git,
+}
+
+impl fmt::Display for HistoryBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HistoryBackend::Files => write!(f, "files"),
+            HistoryBackend::Git => write!(f, "git"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+pub enum ForwardedHeader {
+    #[serde(rename = "x-forwarded-for")]
+    XForwardedFor,
+    // RFC 7239
+    #[serde(rename = "forwarded")]
+    Forwarded,
+}
+
+impl fmt::Display for ForwardedHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ForwardedHeader::XForwardedFor => write!(f, "x-forwarded-for"),
+            ForwardedHeader::Forwarded => write!(f, "forwarded"),
+        }
+    }
+}
+
+pub fn default_geoip_files() -> Vec<String> {
+    let config_dir = config_source().config_dir();
+    ["dbip-country-ipv4-num.csv", "dbip-country-ipv6.csv"].iter()
+        .map(|name| config_dir.join(name).to_string_lossy().into_owned())
+        .collect()
+}
+
+fn default_data_dir() -> String {
+    String::from("./data")
+}
+
+fn default_log_dir() -> String {
+    String::from("./logs")
+}
+
+fn default_backup_dir() -> String {
+    String::from("./backups")
+}
+
+fn default_bind() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 3000))
+}
+
+fn default_geoip_watch_interval() -> u64 {
+    60
+}
+
+fn default_retention_interval() -> u64 {
+    60 * 60
+}
+
+fn default_history_backend() -> HistoryBackend {
+    HistoryBackend::Files
+}
+
+fn default_config_watch_interval() -> u64 {
+    10
+}
+
+fn default_invalid_ip_action() -> PolicyAction {
+    PolicyAction::Deny
+}
+
+fn default_forwarded_header() -> ForwardedHeader {
+    ForwardedHeader::XForwardedFor
+}
+
+// keep the ranges in sync with Settings::violations
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
 pub struct Settings  {
     pub rotate_type: RotateType,
+    #[schemars(range(min = 1, max = 100000))]
     pub rotate_count: u32,
+    #[schemars(range(min = 1, max = 36500))]
     pub rotate_time: u32,
+    #[schemars(range(min = 1, max = 4095))]
     pub rotate_size: u32,
+    // combined history retention rules, replaces rotate_type when set
+    pub retention: Option<RetentionSettings>,
+    // by seconds, how often the retention worker prunes the history, 0 disables it, read at startup only
+    #[serde(default = "default_retention_interval")]
+    pub retention_interval: u64,
+    // where the history is kept, switching does not migrate existing history, read at startup only
+    #[serde(default = "default_history_backend")]
+    pub history_backend: HistoryBackend,
     pub enable_region_block: bool,
+    #[schemars(inner(regex(pattern = r"^([A-Z]{2}|unknown)$")))]
     pub white_region_code_list: Vec<String>,
+    // users.txt, tokens, tabs and history live here, read at startup only
+    #[serde(default = "default_data_dir")]
+    pub data_dir: String,
+    // read at startup only
+    #[serde(default = "default_log_dir")]
+    pub log_dir: String,
+    // backup archives are written here, must be outside data_dir, read at startup only
+    #[serde(default = "default_backup_dir")]
+    pub backup_dir: String,
+    // address the web server listens on, read at startup only
+    #[serde(default = "default_bind")]
+    #[schemars(with = "String")]
+    pub bind: SocketAddr,
+    // replaces white_region_code_list when set
+    #[serde(default)]
+    pub region_policy: Option<RegionPolicy>,
+    // route group (root, login, read, write, admin) -> policy used instead of the region policy
+    #[serde(default)]
+    pub route_policies: HashMap<String, RegionPolicy>,
+    // csv or .mmdb country databases
+    #[serde(default = "default_geoip_files")]
+    pub geoip_files: Vec<String>,
+    // csv or .mmdb ASN databases, optional
+    #[serde(default)]
+    pub asn_files: Vec<String>,
+    // list name -> file with one ip or CIDR per line, used by deny_lists of a region policy
+    #[serde(default)]
+    pub ip_lists: HashMap<String, String>,
+    // by seconds, how often the GeoIP files are checked for changes, 0 disables the check
+    #[serde(default = "default_geoip_watch_interval")]
+    pub geoip_watch_interval: u64,
+    // by seconds, how often appsettings.json is checked for changes, 0 disables the check
+    #[serde(default = "default_config_watch_interval")]
+    pub config_watch_interval: u64,
+    // CIDRs of reverse proxies allowed to set X-Forwarded-For / Forwarded
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub trusted_proxies: Vec<IpNet>,
+    // the header the trusted proxies write, the other one is ignored since clients can send it
+    #[serde(default = "default_forwarded_header")]
+    pub forwarded_header: ForwardedHeader,
+    // what to do with a client address that can not be parsed
+    #[serde(default = "default_invalid_ip_action")]
+    pub invalid_ip_action: PolicyAction,
+    // temporary bans of ips causing too many error responses
+    #[serde(default)]
+    pub ban: BanSettings,
+    // users allowed to call /api/admin endpoints
+    #[serde(default)]
+    pub admin_users: Vec<String>,
+    #[serde(default)]
+    pub oidc: Option<OidcSettings>,
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec![String::from("openid"), String::from("profile"), String::from("email")]
+}
+
+// claims the user can not pick at the provider, email only counts when email_verified is true
+pub const STABLE_OIDC_CLAIMS: [&str; 2] = ["sub", "email"];
+
+fn default_oidc_username_claim() -> String {
+    String::from("preferred_username")
+}
+
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct OidcSettings {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    // must point at /api/oidc/callback of this server
+    pub redirect_uri: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+    // id token claim used as the username, without username_map only sub or a verified email
+    #[serde(default = "default_oidc_username_claim")]
+    pub username_claim: String,
+    // optional claim value -> username mapping, when set only mapped identities can log in
+    #[serde(default)]
+    pub username_map: HashMap<String, String>,
 }
 
 impl Settings  {
@@ -58,24 +269,123 @@ impl Settings  {
             rotate_count: 100,
             rotate_time: 30,
             rotate_size: 200,
+            retention: None,
+            retention_interval: default_retention_interval(),
+            history_backend: default_history_backend(),
             enable_region_block: true,
             white_region_code_list: vec![String::from("SG")],
+            data_dir: default_data_dir(),
+            log_dir: default_log_dir(),
+            backup_dir: default_backup_dir(),
+            bind: default_bind(),
+            region_policy: None,
+            route_policies: HashMap::new(),
+            geoip_files: default_geoip_files(),
+            asn_files: Vec::new(),
+            ip_lists: HashMap::new(),
+            geoip_watch_interval: default_geoip_watch_interval(),
+            config_watch_interval: default_config_watch_interval(),
+            trusted_proxies: Vec::new(),
+            forwarded_header: default_forwarded_header(),
+            invalid_ip_action: default_invalid_ip_action(),
+            ban: BanSettings::default(),
+            admin_users: Vec::new(),
+            oidc: None,
         }
     }
 
-    pub fn from_file(filename: &str) -> Self {
-        let contents = fs::read_to_string(filename).unwrap();
-        let settings: Settings = serde_json::from_str(&contents).unwrap();
-        settings
+    pub fn validate(&self) -> Result<(), String> {
+        let violations = self.violations();
+        if violations.is_empty() {
+            return Ok(());
+        }
+        Err(format!("Invalid settings:\n  {}", violations.join("\n  ")))
     }
-    
-    pub fn contains_region(&self, region: &str) -> bool {
-        for white_region in &self.white_region_code_list {
-            if white_region == region {
-                return true;
+
+    /// Every rule the settings break, prefixed with the json path of the value.
+    pub fn violations(&self) -> Vec<String> {
+        let mut violations = Vec::new();
+        if self.rotate_type == RotateType::Reserved {
+            violations.push("settings.rotate_type: reserved is not supported".to_string());
+        }
+        // rotate_time and rotate_size are turned into seconds and bytes as u32
+        for (name, value, max) in [
+            ("rotate_count", self.rotate_count, 100000),
+            ("rotate_time", self.rotate_time, 36500),
+            ("rotate_size", self.rotate_size, 4095),
+        ] {
+            if value == 0 || value > max {
+                violations.push(format!("settings.{}: {} is not between 1 and {}", name, value, max));
+            }
+        }
+        if let Some(retention) = &self.retention {
+            violations.extend(retention.violations("settings.retention"));
+        }
+        violations.extend(region_code_violations("settings.white_region_code_list", &self.white_region_code_list));
+        if let Some(region_policy) = &self.region_policy {
+            violations.extend(region_policy.violations("settings.region_policy"));
+        }
+        let mut route_groups = self.route_policies.keys().collect::<Vec<&String>>();
+        route_groups.sort();
+        for route_group in route_groups {
+            violations.extend(self.route_policies[route_group].violations(&format!("settings.route_policies.{}", route_group)));
+        }
+        for (name, value) in [("find_time", self.ban.find_time), ("ban_time", self.ban.ban_time)] {
+            if value <= 0 {
+                violations.push(format!("settings.ban.{}: {} must be positive", name, value));
             }
         }
-        false
+        if self.ban.max_failures == 0 {
+            violations.push("settings.ban.max_failures: must be positive".to_string());
+        }
+        // a backup would stage and archive the earlier archives
+        if absolute_path(&self.backup_dir).starts_with(absolute_path(&self.data_dir)) {
+            violations.push(format!("settings.backup_dir: {} must be outside data_dir {}", self.backup_dir, self.data_dir));
+        }
+        if let Some(oidc) = &self.oidc {
+            if !oidc.scopes.iter().any(|scope| scope == "openid") {
+                violations.push("settings.oidc.scopes: must contain openid".to_string());
+            }
+            if oidc.username_map.is_empty() && !STABLE_OIDC_CLAIMS.contains(&oidc.username_claim.as_str()) {
+                violations.push(format!("settings.oidc.username_claim: {} may be chosen by the user at the provider, \
+                    use sub or email, or map identities with username_map", oidc.username_claim));
+            }
+        }
+        violations
+    }
+
+    /// The retention rules, or the single rule picked by rotate_type.
+    pub fn retention(&self) -> RetentionSettings {
+        if let Some(retention) = &self.retention {
+            return retention.clone();
+        }
+        match self.rotate_type {
+            RotateType::HistoryCount => RetentionSettings { max_keep: Some(self.rotate_count), ..Default::default() },
+            RotateType::StoredTime => RetentionSettings { max_age: Some(self.rotate_time), ..Default::default() },
+            RotateType::TotalSize => RetentionSettings { max_size: Some(self.rotate_size), ..Default::default() },
+            RotateType::Tiered => RetentionSettings { tiered: true, ..Default::default() },
+            RotateType::Reserved => RetentionSettings::default(),
+        }
+    }
+
+    pub fn region_policy(&self) -> RegionPolicy {
+        match &self.region_policy {
+            Some(value) => value.clone(),
+            None => RegionPolicy::from_white_list(&self.white_region_code_list),
+        }
+    }
+
+    pub fn route_policy(&self, route_group: &str) -> RegionPolicy {
+        match self.route_policies.get(route_group) {
+            Some(value) => value.clone(),
+            None => self.region_policy(),
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings::new()
     }
 }
 
@@ -83,11 +393,31 @@ impl Clone for Settings {
     fn clone(&self) -> Self {
         Settings {
             rotate_type: self.rotate_type.clone(),
-            rotate_count: self.rotate_count.clone(),
-            rotate_time: self.rotate_time.clone(),
-            rotate_size: self.rotate_size.clone(),
-            enable_region_block: self.enable_region_block.clone(),
+            rotate_count: self.rotate_count,
+            rotate_time: self.rotate_time,
+            rotate_size: self.rotate_size,
+            retention: self.retention.clone(),
+            retention_interval: self.retention_interval,
+            history_backend: self.history_backend,
+            enable_region_block: self.enable_region_block,
             white_region_code_list: self.white_region_code_list.clone(),
+            data_dir: self.data_dir.clone(),
+            log_dir: self.log_dir.clone(),
+            backup_dir: self.backup_dir.clone(),
+            bind: self.bind,
+            region_policy: self.region_policy.clone(),
+            route_policies: self.route_policies.clone(),
+            geoip_files: self.geoip_files.clone(),
+            asn_files: self.asn_files.clone(),
+            ip_lists: self.ip_lists.clone(),
+            geoip_watch_interval: self.geoip_watch_interval,
+            config_watch_interval: self.config_watch_interval,
+            trusted_proxies: self.trusted_proxies.clone(),
+            forwarded_header: self.forwarded_header,
+            invalid_ip_action: self.invalid_ip_action,
+            ban: self.ban.clone(),
+            admin_users: self.admin_users.clone(),
+            oidc: self.oidc.clone(),
         }
     }
 }
@@ -98,13 +428,105 @@ impl fmt::Display for Settings {
             Rotate count: {}\n \
             Rotate time: {} days\n \
             Rotate size: {} MB\n \
+            Retention: {:?}\n \
+            Retention interval: {} seconds\n \
+            History backend: {}\n \
             Enable region block: {}\n \
-            White region code list: {:?}",
-            self.rotate_type, self.rotate_count, self.rotate_time, self.rotate_size, self.enable_region_block, self.white_region_code_list)
+            White region code list: {:?}\n \
+            Data directory: {}\n \
+            Log directory: {}\n \
+            Backup directory: {}\n \
+            Bind: {}\n \
+            Region policy: {:?}\n \
+            Route policies: {:?}\n \
+            GeoIP files: {:?}\n \
+            ASN files: {:?}\n \
+            IP lists: {:?}\n \
+            GeoIP watch interval: {} seconds\n \
+            Config watch interval: {} seconds\n \
+            Trusted proxies: {:?}\n \
+            Forwarded header: {}\n \
+            Invalid ip action: {}\n \
+            Ban: {:?}\n \
+            Admin users: {:?}\n \
+            OIDC issuer: {}",
+            self.rotate_type, self.rotate_count, self.rotate_time, self.rotate_size, self.retention(), self.retention_interval, self.history_backend, self.enable_region_block, self.white_region_code_list,
+            self.data_dir, self.log_dir, self.backup_dir, self.bind,
+            self.region_policy, self.route_policies,
+            self.geoip_files, self.asn_files, self.ip_lists, self.geoip_watch_interval, self.config_watch_interval, self.trusted_proxies, self.forwarded_header, self.invalid_ip_action, self.ban, self.admin_users,
+            self.oidc.as_ref().map(|oidc| oidc.issuer.as_str()).unwrap_or("disabled"))
+    }
+}
+
+lazy_static! {
+    // the one shared config, replaced as a whole on reload
+    pub static ref CONFIG_INSTANCE: ArcSwap<Config> = ArcSwap::from_pointee(Config::new());
+}
+
+static CONFIG_SOURCE: OnceLock<ConfigSource> = OnceLock::new();
+
+const SETTINGS_FILE_NAMES: [&str; 4] = ["appsettings.json", "appsettings.toml", "appsettings.yaml", "appsettings.yml"];
+
+/// Where the settings come from, in increasing priority: defaults, the settings file,
+/// `BOT_*` environment variables and command line flags.
+#[derive(Clone, Default)]
+pub struct ConfigSource {
+    // directory searched for appsettings.json, .toml or .yaml, default ./config
+    pub config_dir: Option<String>,
+    // settings file used instead of searching config_dir
+    pub config_file: Option<String>,
+    // settings given on the command line
+    pub overrides: serde_json::Map<String, serde_json::Value>,
+    // replaces the port of bind
+    pub port: Option<u16>,
+}
+
+impl ConfigSource {
+    pub fn config_dir(&self) -> PathBuf {
+        PathBuf::from(self.config_dir.as_deref().unwrap_or("./config"))
+    }
+
+    /// The explicit settings file, or the first settings file found in the config directory.
+    pub fn config_file(&self) -> Option<PathBuf> {
+        if let Some(config_file) = &self.config_file {
+            return Some(PathBuf::from(config_file));
+        }
+        let config_dir = self.config_dir();
+        SETTINGS_FILE_NAMES.iter()
+            .map(|name| config_dir.join(name))
+            .find(|path| path.exists())
+    }
+}
+
+/// `path` from the working directory, with `.` and `..` resolved without touching the file system.
+fn absolute_path(path: &str) -> PathBuf {
+    let path = std::path::absolute(path).unwrap_or_else(|_| PathBuf::from(path));
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Must be called before the config is first used, later calls are ignored.
+pub fn set_config_source(source: ConfigSource) {
+    if CONFIG_SOURCE.set(source).is_err() {
+        warn!("Config source is already set");
     }
 }
 
-#[derive(Deserialize)]
+pub fn config_source() -> &'static ConfigSource {
+    CONFIG_SOURCE.get_or_init(ConfigSource::default)
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub settings: Settings,
 }
@@ -116,49 +538,206 @@ impl fmt::Display for Config {
 }
 
 impl Config{
-    fn build(settings: Settings) -> Self {
-        Config {
-            settings,
+    /// Falls back to the default settings, startup checks the config with `Config::load` before.
+    pub fn new() -> Self {
+        match Config::load(config_source()) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("{}, use default settings", e);
+                Config {
+                    settings: Settings::new(),
+                }
+            }
         }
     }
 
-    pub fn new() -> Self {
-        let config_string = match fs::read_to_string("./config/appsettings.json") {
-            Ok(value) => {
-                info!("Loaded appsettings.json");
-                value
-            },
-            Err(_) => {
-                warn!("Failed to read appsettings.json, use default settings");
-                let default_config = Config {
-                    settings: Settings::new(),
-                };
-                return default_config;
+    pub fn load(source: &ConfigSource) -> Result<Self, String> {
+        Config::load_with_env(source, std::env::vars())
+    }
+
+    /// Like `load`, with the `BOT_` variables taken from `env` instead of the process environment.
+    fn load_with_env(source: &ConfigSource, env: impl IntoIterator<Item = (String, String)>) -> Result<Self, String> {
+        let mut value = serde_json::to_value(Settings::new()).unwrap();
+        match source.config_file() {
+            Some(path) => merge_value(&mut value, read_settings_file(&path)?),
+            None => warn!("No settings file in {}, use default settings", source.config_dir().display()),
+        }
+        for (key, env_value) in env {
+            let name = match key.strip_prefix("BOT_") {
+                Some(name) if name != "CONFIG" && name != "CONFIG_DIR" => name.to_lowercase(),
+                _ => continue,
+            };
+            let path = name.split("__").collect::<Vec<&str>>();
+            // the prefix may be shared with unrelated variables of the container or orchestrator
+            if value.get(path[0]).is_none() {
+                warn!("Ignore environment variable {}, {} is not a setting", key, path[0]);
+                continue;
             }
-        };
-        let config: Config = match serde_json::from_str(&config_string) {
-            Ok(value) => value,
-            Err(_) => {
-                let default_config = Config {
-                    settings: Settings::new(),
-                };
-                return default_config;
+            // plain strings do not need quotes
+            let env_value = serde_json::from_str(&env_value).unwrap_or(serde_json::Value::String(env_value));
+            set_value(&mut value, &path, env_value);
+        }
+        merge_value(&mut value, serde_json::Value::Object(source.overrides.clone()));
+
+        let mut settings: Settings = serde_path_to_error::deserialize(value)
+            .map_err(|e| format!("Invalid setting settings.{}: {}", e.path(), e.inner()))?;
+        if let Some(port) = source.port {
+            settings.bind.set_port(port);
+        }
+        settings.validate()?;
+        for policy in settings.route_policies.values().chain(settings.region_policy.iter()) {
+            for list_name in &policy.deny_lists {
+                if !settings.ip_lists.contains_key(list_name) {
+                    warn!("Unknown ip list {} in deny_lists", list_name);
+                }
             }
-        };
-        info!("Loaded config: {}", config);
-        config
+        }
+        for route_group in settings.route_policies.keys() {
+            if !ROUTE_GROUPS.contains(&route_group.as_str()) {
+                warn!("Unknown route group {} in route_policies, known groups: {:?}", route_group, ROUTE_GROUPS);
+            }
+        }
+        Ok(Config { settings })
     }
 }
 
+/// JSON Schema of appsettings.json, published as config/appsettings.schema.json.
+pub fn settings_schema() -> String {
+    serde_json::to_string_pretty(&schemars::schema_for!(Config)).unwrap()
+}
+
+/// Reads the `settings` object of a json, toml or yaml file, by extension.
+fn read_settings_file(path: &Path) -> Result<serde_json::Value, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+    let value: serde_json::Value = match extension {
+        "toml" => toml::from_str(&contents).map_err(|e| e.to_string()),
+        "yaml" | "yml" => serde_yaml::from_str(&contents).map_err(|e| e.to_string()),
+        _ => serde_json::from_str(&contents).map_err(|e| e.to_string()),
+    }.map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+    if let Some(key) = value.as_object().and_then(|object| object.keys().find(|key| *key != "settings" && *key != "$schema")) {
+        return Err(format!("Unknown top level key {} in {}, expected settings", key, path.display()));
+    }
+    match value.get("settings") {
+        Some(settings) if settings.is_object() => Ok(settings.clone()),
+        _ => Err(format!("Missing settings object in {}", path.display())),
+    }
+}
+
+/// Objects are merged key by key, anything else is replaced.
+fn merge_value(base: &mut serde_json::Value, layer: serde_json::Value) {
+    match (base, layer) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(layer)) => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge_value(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, layer) => *base = layer,
+    }
+}
+
+fn set_value(base: &mut serde_json::Value, path: &[&str], value: serde_json::Value) {
+    if path.is_empty() {
+        *base = value;
+        return;
+    }
+    if !base.is_object() {
+        *base = serde_json::Value::Object(serde_json::Map::new());
+    }
+    let child = base.as_object_mut().unwrap()
+        .entry(path[0].to_string())
+        .or_insert(serde_json::Value::Null);
+    set_value(child, &path[1..], value);
+}
+
+/// Settings the server reads once at startup, the statics and workers built from them stay bound
+/// to the startup values, so a reload keeps them.
+const STARTUP_SETTINGS: [&str; 6] = ["data_dir", "log_dir", "bind", "backup_dir", "retention_interval", "history_backend"];
+
+/// Copies the startup only settings from `current` into `new`, returns the ones that differed.
+fn keep_startup_settings(current: &Settings, new: &mut Settings) -> Vec<&'static str> {
+    let (current_value, new_value) = (settings_value(current), settings_value(new));
+    let changed = STARTUP_SETTINGS.iter()
+        .filter(|key| current_value.get(**key) != new_value.get(**key))
+        .copied()
+        .collect();
+    new.data_dir = current.data_dir.clone();
+    new.log_dir = current.log_dir.clone();
+    new.bind = current.bind;
+    new.backup_dir = current.backup_dir.clone();
+    new.retention_interval = current.retention_interval;
+    new.history_backend = current.history_backend;
+    changed
+}
+
+/// Reads and validates the config file, then swaps it in. On error the current config stays.
+/// Startup only settings keep their current value until a restart. Returns the changed settings.
+pub fn reload() -> Result<Vec<String>, String> {
+    let config = Config::load(config_source());
+    let mut config = match config {
+        Ok(value) => value,
+        Err(e) => {
+            error!("Keep current config: {}", e);
+            return Err(e);
+        }
+    };
+    let current = CONFIG_INSTANCE.load();
+    for key in keep_startup_settings(&current.settings, &mut config.settings) {
+        warn!("Config changed: {} is read at startup only, restart to apply it", key);
+    }
+    let changes = diff_settings(&current.settings, &config.settings);
+    if changes.is_empty() {
+        info!("Reloaded config, nothing changed");
+    }
+    for change in &changes {
+        info!("Config changed: {}", change);
+    }
+    CONFIG_INSTANCE.store(Arc::new(config));
+    Ok(changes)
+}
+
+fn settings_value(settings: &Settings) -> serde_json::Map<String, serde_json::Value> {
+    let mut value = serde_json::to_value(settings).unwrap();
+    // keep secrets out of the logs
+    if let Some(oidc) = value.get_mut("oidc").and_then(|oidc| oidc.as_object_mut()) {
+        oidc.insert("client_secret".to_string(), serde_json::Value::String("***".to_string()));
+    }
+    match value {
+        serde_json::Value::Object(map) => map,
+        _ => serde_json::Map::new(),
+    }
+}
+
+/// One `key: old -> new` line per changed top level setting.
+pub fn diff_settings(old: &Settings, new: &Settings) -> Vec<String> {
+    let (old, new) = (settings_value(old), settings_value(new));
+    let mut changes = Vec::new();
+    for (key, new_value) in &new {
+        let old_value = old.get(key).unwrap_or(&serde_json::Value::Null);
+        if old_value != new_value {
+            changes.push(format!("{}: {} -> {}", key, old_value, new_value));
+        }
+    }
+    changes
+}
+
 // test module
 #[cfg(test)]
 mod tests {
     lazy_static! {
         pub static ref CONFIG_INSTANCE: Mutex<Config> = Mutex::new(Config::new());
     }
+    use std::sync::Mutex;
     use super::*;
 
     #[test]
+    #[allow(clippy::assertions_on_constants, clippy::bool_comparison, clippy::len_zero)]
     fn test_all_config_fields() {
         let config = match CONFIG_INSTANCE.lock(){
             Ok(value) => value,
@@ -174,4 +753,188 @@ mod tests {
         assert!(config.settings.enable_region_block == true || config.settings.enable_region_block == false);
         assert!(config.settings.white_region_code_list.len() > 0);
     }
+
+    #[test]
+    fn test_validate() {
+        let mut settings = Settings::new();
+        assert!(settings.validate().is_ok());
+        settings.rotate_type = RotateType::Reserved;
+        assert!(settings.validate().is_err());
+        settings.rotate_type = RotateType::TotalSize;
+        settings.rotate_size = 0;
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn test_backup_dir_outside_data_dir() {
+        let mut settings = Settings::new();
+        assert!(settings.violations().is_empty());
+        for backup_dir in ["./data/backups", "data", "./backups/../data/./backups"] {
+            settings.backup_dir = String::from(backup_dir);
+            assert_eq!(settings.violations(), vec![format!("settings.backup_dir: {} must be outside data_dir ./data", backup_dir)]);
+        }
+        settings.backup_dir = String::from("./data-backups");
+        assert!(settings.violations().is_empty());
+    }
+
+    #[test]
+    fn test_violations() {
+        let mut settings = Settings::new();
+        settings.rotate_type = RotateType::Reserved;
+        settings.rotate_size = 5000;
+        settings.white_region_code_list = vec![String::from("SG"), String::from("sg"), String::from("unknown")];
+        let mut route_policy = RegionPolicy::from_white_list(&[String::from("MYS")]);
+        route_policy.deny_regions.push(String::from("ES"));
+        settings.route_policies.insert(String::from("login"), route_policy);
+        assert_eq!(settings.violations(), vec![
+            String::from("settings.rotate_type: reserved is not supported"),
+            String::from("settings.rotate_size: 5000 is not between 1 and 4095"),
+            String::from(r#"settings.white_region_code_list[1]: "sg" is not an ISO 3166 alpha-2 region code"#),
+            String::from(r#"settings.route_policies.login.allow_regions[0]: "MYS" is not an ISO 3166 alpha-2 region code"#),
+        ]);
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        let path = write_settings_file("unknown.json", r#"{ "settings": { "region_policy": { "allow_region": ["SG"] } } }"#);
+        let source = ConfigSource { config_file: Some(path.to_string_lossy().into_owned()), ..Default::default() };
+        let error = Config::load(&source).err().unwrap();
+        assert!(error.starts_with("Invalid setting settings.region_policy"), "{}", error);
+        assert!(error.contains("unknown field `allow_region`"), "{}", error);
+
+        let path = write_settings_file("top-level.json", r#"{ "setings": {} }"#);
+        let source = ConfigSource { config_file: Some(path.to_string_lossy().into_owned()), ..Default::default() };
+        assert!(Config::load(&source).err().unwrap().starts_with("Unknown top level key setings"));
+    }
+
+    #[test]
+    fn test_schema_is_published() {
+        let published = fs::read_to_string("./config/appsettings.schema.json").unwrap();
+        assert_eq!(published.trim_end(), settings_schema(),
+            "regenerate with `better-one-tab-2024-server schema > config/appsettings.schema.json`");
+    }
+
+    fn write_settings_file(name: &str, contents: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bot-config-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_load_layers() {
+        let path = write_settings_file("appsettings.toml", r#"
+[settings]
+rotate_type = "total_size"
+rotate_count = 5
+rotate_time = 1
+rotate_size = 10
+enable_region_block = false
+white_region_code_list = ["MY"]
+bind = "127.0.0.1:8080"
+"#);
+        let env = vec![
+            (String::from("BOT_LOG_DIR"), String::from("/var/log/bot")),
+            // not a setting, ignored with a warning
+            (String::from("BOT_VERSION"), String::from("1.2.3")),
+        ];
+        let mut source = ConfigSource {
+            config_file: Some(path.to_string_lossy().into_owned()),
+            port: Some(9000),
+            ..Default::default()
+        };
+        source.overrides.insert(String::from("data_dir"), serde_json::Value::String(String::from("/srv/bot")));
+        let settings = Config::load_with_env(&source, env).unwrap().settings;
+
+        assert_eq!(settings.rotate_type, RotateType::TotalSize);
+        assert_eq!(settings.rotate_count, 5);
+        assert_eq!(settings.white_region_code_list, vec![String::from("MY")]);
+        // not in the file, keeps the default
+        assert_eq!(settings.geoip_watch_interval, 60);
+        assert_eq!(settings.log_dir, "/var/log/bot");
+        assert_eq!(settings.data_dir, "/srv/bot");
+        assert_eq!(settings.bind, "127.0.0.1:9000".parse::<SocketAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_load_yaml_and_errors() {
+        let path = write_settings_file("appsettings.yaml", "settings:\n  rotate_count: 7\n");
+        let source = ConfigSource { config_file: Some(path.to_string_lossy().into_owned()), ..Default::default() };
+        assert_eq!(Config::load(&source).unwrap().settings.rotate_count, 7);
+
+        let path = write_settings_file("appsettings.json", r#"{ "settings": { "ban": { "max_failures": "ten" } } }"#);
+        let source = ConfigSource { config_file: Some(path.to_string_lossy().into_owned()), ..Default::default() };
+        let error = Config::load(&source).err().unwrap();
+        assert!(error.contains("settings.ban.max_failures"), "{}", error);
+
+        let source = ConfigSource { config_file: Some(String::from("./config/missing.json")), ..Default::default() };
+        assert!(Config::load(&source).err().unwrap().starts_with("Failed to read"));
+    }
+
+    #[test]
+    fn test_set_value() {
+        let mut value = serde_json::json!({ "oidc": null, "ban": { "enabled": false } });
+        set_value(&mut value, &["oidc", "client_secret"], serde_json::json!("secret"));
+        set_value(&mut value, &["ban", "enabled"], serde_json::json!(true));
+        assert_eq!(value, serde_json::json!({ "oidc": { "client_secret": "secret" }, "ban": { "enabled": true } }));
+    }
+
+    #[test]
+    fn test_oidc_username_claim_must_be_stable() {
+        let mut settings = Settings::new();
+        settings.oidc = Some(OidcSettings {
+            issuer: String::from("https://id.example.com"),
+            client_id: String::from("tabs"),
+            client_secret: String::from("secret"),
+            redirect_uri: String::from("https://tabs.example.com/api/oidc/callback"),
+            scopes: default_oidc_scopes(),
+            username_claim: default_oidc_username_claim(),
+            username_map: HashMap::new(),
+        });
+        assert!(settings.violations().iter().any(|violation| violation.starts_with("settings.oidc.username_claim: preferred_username")));
+        settings.oidc.as_mut().unwrap().username_map.insert(String::from("alice@example.com"), String::from("alice"));
+        assert!(settings.violations().is_empty());
+        let oidc = settings.oidc.as_mut().unwrap();
+        oidc.username_map.clear();
+        oidc.username_claim = String::from("sub");
+        assert!(settings.violations().is_empty());
+    }
+
+    #[test]
+    fn test_diff_settings() {
+        let old = Settings::new();
+        let mut new = Settings::new();
+        assert!(diff_settings(&old, &new).is_empty());
+
+        new.rotate_count = 5;
+        new.white_region_code_list.push(String::from("MY"));
+        new.oidc = Some(OidcSettings {
+            issuer: String::from("https://id.example.com"),
+            client_id: String::from("tabs"),
+            client_secret: String::from("secret"),
+            redirect_uri: String::from("https://tabs.example.com/api/oidc/callback"),
+            scopes: Vec::new(),
+            username_claim: String::from("email"),
+            username_map: HashMap::new(),
+        });
+        let changes = diff_settings(&old, &new);
+        assert_eq!(changes.len(), 3);
+        assert!(changes.contains(&String::from("rotate_count: 100 -> 5")));
+        assert!(changes.contains(&String::from(r#"white_region_code_list: ["SG"] -> ["SG","MY"]"#)));
+        assert!(changes.iter().all(|change| !change.contains("\"secret\"")));
+    }
+    #[test]
+    fn test_reload_keeps_startup_settings() {
+        let current = Settings::new();
+        let mut new = Settings::new();
+        new.data_dir = String::from("/srv/bot");
+        new.bind.set_port(9000);
+        new.rotate_count = 5;
+        assert_eq!(keep_startup_settings(&current, &mut new), vec!["data_dir", "bind"]);
+        assert_eq!(new.data_dir, current.data_dir);
+        assert_eq!(new.bind, current.bind);
+        assert_eq!(new.rotate_count, 5);
+        assert!(keep_startup_settings(&current, &mut new).is_empty());
+    }
 }
\ No newline at end of file
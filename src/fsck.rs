@@ -0,0 +1,478 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use log::{error, info};
+use serde::Serialize;
+use crate::audit;
+use crate::config::CONFIG_INSTANCE;
+use crate::git_history;
+use crate::history::{self, meta_path, MANIFEST_EXTENSION, META_EXTENSION};
+use crate::labels::Labels;
+use crate::models::tabs::TabGroup;
+use crate::oidc::{read_oidc_users, OIDC_USERS_FILE};
+use crate::util::{load_tabs, lock_file, read_users};
+
+// json files of the data directory that are not tabs
+const NOT_TABS: [&str; 1] = ["bans.json"];
+// entries of the history directory that are not snapshot directories
+const HISTORY_ENTRIES: [&str; 3] = ["objects", "git", "labels.json"];
+// temp files and empty snapshot directories younger than this may belong to a write in progress
+const GRACE_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    // a current tabs file that does not parse, repaired like a fetch does
+    CorruptTabs,
+    // a snapshot that can not be read or does not parse, repaired by moving it to quarantine
+    CorruptSnapshot,
+    // report only, the extension decides which copy wins
+    DuplicateUuid,
+    // a token of a user missing from users.txt and the OIDC users, repaired by removing it
+    OrphanToken,
+    // a history directory without snapshots or a sidecar without its snapshot, repaired by removing it
+    OrphanHistory,
+    // a history directory that is not a unix time, report only
+    InvalidHistoryDir,
+    // objects no snapshot refers to, repaired by removing them
+    UnreferencedObject,
+    // left behind by an interrupted write, repaired by removing it
+    TempFile,
+}
+
+impl fmt::Display for IssueKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", serde_json::to_value(self).unwrap().as_str().unwrap())
+    }
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Issue {
+    pub kind: IssueKind,
+    // relative to the data directory
+    pub path: String,
+    pub message: String,
+    pub repaired: bool,
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct FsckReport {
+    pub repair: bool,
+    pub tabs_files: usize,
+    pub snapshots: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl FsckReport {
+    pub fn unrepaired(&self) -> usize {
+        self.issues.iter().filter(|issue| !issue.repaired).count()
+    }
+
+    fn add(&mut self, kind: IssueKind, path: &str, message: String) {
+        self.issues.push(Issue { kind, path: path.to_string(), message, repaired: false });
+    }
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for issue in &self.issues {
+            let repaired = if issue.repaired { " (repaired)" } else { "" };
+            writeln!(f, "{} {}: {}{}", issue.kind, issue.path, issue.message, repaired)?;
+        }
+        write!(f, "checked {} tabs files and {} snapshots, {} issues, {} repaired",
+            self.tabs_files, self.snapshots, self.issues.len(), self.issues.len() - self.unrepaired())
+    }
+}
+
+/// Uuids used by more than one group, or by more than one tab.
+pub fn duplicate_uuids(groups: &[TabGroup]) -> Vec<String> {
+    let mut duplicates = Vec::new();
+    let (mut group_uuids, mut tab_uuids) = (HashSet::new(), HashSet::new());
+    for group in groups {
+        if !group_uuids.insert(group.uuid.as_str()) {
+            duplicates.push(format!("group {}", group.uuid));
+        }
+        for tab in &group.tabs {
+            if !tab_uuids.insert(tab.uuid.as_str()) {
+                duplicates.push(format!("tab {}", tab.uuid));
+            }
+        }
+    }
+    duplicates
+}
+
+fn check_tabs(report: &mut FsckReport, path: &str, contents: Result<String, std::io::Error>, kind: IssueKind) -> bool {
+    let groups = contents.and_then(|contents| serde_json::from_str::<Vec<TabGroup>>(&contents).map_err(std::io::Error::from));
+    match groups {
+        Ok(groups) => {
+            let duplicates = duplicate_uuids(&groups);
+            if !duplicates.is_empty() {
+                report.add(IssueKind::DuplicateUuid, path, format!("duplicate {}", duplicates.join(", ")));
+            }
+            true
+        }
+        Err(e) => {
+            report.add(kind, path, e.to_string());
+            false
+        }
+    }
+}
+
+fn relative(data_dir: &Path, path: &Path) -> String {
+    path.strip_prefix(data_dir).unwrap_or(path).to_string_lossy().into_owned()
+}
+
+/// The user a file belongs to, `history/100/alice.json` is alice's, a directory or objects belong to nobody.
+fn username_of(path: &str) -> String {
+    let name = path.split('@').next().unwrap_or_default().rsplit('/').next().unwrap_or_default();
+    let username = name.trim_start_matches('.').split('.').next().unwrap_or_default();
+    if username == name || username.parse::<i64>().is_ok() {
+        return String::new();
+    }
+    username.to_string()
+}
+
+fn is_temp_file(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(".tmp")
+}
+
+/// Changed within the grace period, or gone meanwhile.
+fn is_recent(path: &Path) -> bool {
+    fs::metadata(path).and_then(|metadata| metadata.modified())
+        .map(|modified| modified.elapsed().unwrap_or_default() < GRACE_PERIOD)
+        .unwrap_or(true)
+}
+
+/// Snapshot file names in a snapshot directory, without manifest extension, sidecars and temp files.
+fn snapshot_names(snapshot_dir: &Path) -> Result<HashSet<String>, std::io::Error> {
+    Ok(fs::read_dir(snapshot_dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|file| !file.ends_with(META_EXTENSION) && !file.starts_with('.'))
+        .map(|file| file.strip_suffix(MANIFEST_EXTENSION).unwrap_or(&file).to_string())
+        .collect())
+}
+
+/// Users of users.txt and those who signed in through OIDC.
+fn known_users(data_dir: &Path) -> Result<HashSet<String>, std::io::Error> {
+    let mut users = match read_users(&data_dir.join("users.txt")) {
+        Ok(users) => users.into_iter().map(|user| user.username).collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+        Err(e) => return Err(e),
+    };
+    users.extend(read_oidc_users(&data_dir.join(OIDC_USERS_FILE))?);
+    Ok(users)
+}
+
+fn scan_data_dir(data_dir: &Path, report: &mut FsckReport) -> Result<(), std::io::Error> {
+    let users = known_users(data_dir)?;
+    let mut names = fs::read_dir(data_dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect::<Vec<String>>();
+    names.sort();
+    for name in names {
+        if is_temp_file(&name) {
+            if !is_recent(&data_dir.join(&name)) {
+                report.add(IssueKind::TempFile, &name, String::from("left by an interrupted write"));
+            }
+        } else if let Some(username) = name.strip_suffix(".txt") {
+            if name != "users.txt" && !users.contains(username) {
+                report.add(IssueKind::OrphanToken, &name, format!("{} is not in users.txt or an OIDC user", username));
+            }
+        } else if name.ends_with(".json") && !NOT_TABS.contains(&name.as_str()) {
+            report.tabs_files += 1;
+            check_tabs(report, &name, fs::read_to_string(data_dir.join(&name)), IssueKind::CorruptTabs);
+        }
+    }
+    Ok(())
+}
+
+fn scan_history_dir(data_dir: &Path, history_dir: &Path, report: &mut FsckReport) -> Result<(), std::io::Error> {
+    let mut entries = fs::read_dir(history_dir)?.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect::<Vec<_>>();
+    entries.sort();
+    for path in entries {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if HISTORY_ENTRIES.contains(&name.as_str()) {
+            continue;
+        }
+        if is_temp_file(&name) {
+            if !is_recent(&path) {
+                report.add(IssueKind::TempFile, &relative(data_dir, &path), String::from("left by an interrupted write"));
+            }
+            continue;
+        }
+        let timestamp = match name.parse::<i64>() {
+            Ok(value) if path.is_dir() => value,
+            _ => {
+                report.add(IssueKind::InvalidHistoryDir, &relative(data_dir, &path), String::from("not a unix time snapshot directory"));
+                continue;
+            }
+        };
+        let snapshots = snapshot_names(&path)?;
+        if snapshots.is_empty() {
+            // a snapshot being written creates its directory first
+            if !is_recent(&path) {
+                report.add(IssueKind::OrphanHistory, &relative(data_dir, &path), String::from("no snapshots"));
+            }
+            continue;
+        }
+        let mut files = fs::read_dir(&path)?.filter_map(|entry| entry.ok()).map(|entry| entry.file_name().to_string_lossy().into_owned()).collect::<Vec<String>>();
+        files.sort();
+        for file in &files {
+            let file_path = relative(data_dir, &path.join(file));
+            if is_temp_file(file) {
+                if !is_recent(&path.join(file)) {
+                    report.add(IssueKind::TempFile, &file_path, String::from("left by an interrupted write"));
+                }
+            } else if let Some(snapshot) = file.strip_suffix(META_EXTENSION) {
+                if !snapshots.contains(snapshot) {
+                    report.add(IssueKind::OrphanHistory, &file_path, String::from("sidecar without a snapshot"));
+                }
+            }
+        }
+        let mut snapshots = snapshots.into_iter().collect::<Vec<String>>();
+        snapshots.sort();
+        for filename in snapshots {
+            report.snapshots += 1;
+            let snapshot_path = relative(data_dir, &path.join(&filename));
+            check_tabs(report, &snapshot_path, history::read_snapshot(history_dir, timestamp, &filename), IssueKind::CorruptSnapshot);
+        }
+    }
+
+    match history::unreferenced_objects(history_dir) {
+        Ok(objects) if !objects.is_empty() => {
+            let message = format!("{} objects, {} bytes", objects.len(), objects.values().sum::<u64>());
+            report.add(IssueKind::UnreferencedObject, &relative(data_dir, &history_dir.join("objects")), message);
+        }
+        Ok(_) => {}
+        // an unreadable manifest is already a corrupt snapshot
+        Err(e) => info!("Skipped the unreferenced object check: {}", e),
+    }
+
+    for snapshot in git_history::list_snapshots(history_dir, &Labels::new(), None)? {
+        report.snapshots += 1;
+        let path = format!("{}@{}", relative(data_dir, &git_history::repo_path(history_dir, &snapshot.filename)), snapshot.timestamp);
+        let contents = git_history::read_snapshot(history_dir, snapshot.timestamp, &snapshot.filename)
+            .and_then(|contents| contents.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no commit")));
+        check_tabs(report, &path, contents, IssueKind::CorruptSnapshot);
+    }
+    Ok(())
+}
+
+fn remove(path: &Path) -> Result<(), std::io::Error> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Moves a snapshot with its manifest and sidecar to `quarantine/history-<time>-<file>`.
+fn quarantine_snapshot(data_dir: &Path, snapshot_path: &Path) -> Result<(), std::io::Error> {
+    let quarantine_dir = data_dir.join("quarantine");
+    fs::create_dir_all(&quarantine_dir)?;
+    let timestamp = snapshot_path.parent().and_then(|dir| dir.file_name()).unwrap().to_string_lossy().into_owned();
+    let target = quarantine_dir.join(format!("history-{}-{}", timestamp, snapshot_path.file_name().unwrap().to_string_lossy()));
+    for (from, to) in [
+        (snapshot_path.to_path_buf(), target.clone()),
+        (history::manifest_path(snapshot_path), history::manifest_path(&target)),
+        (meta_path(snapshot_path), meta_path(&target)),
+    ] {
+        if from.exists() {
+            fs::rename(from, to)?;
+        }
+    }
+    let snapshot_dir = snapshot_path.parent().unwrap();
+    if fs::read_dir(snapshot_dir)?.next().is_none() {
+        fs::remove_dir(snapshot_dir)?;
+    }
+    Ok(())
+}
+
+/// Whether the issue still holds, checked again under the locks since syncs run meanwhile.
+fn still_applies(data_dir: &Path, issue: &Issue) -> Result<bool, std::io::Error> {
+    let path = data_dir.join(&issue.path);
+    Ok(match issue.kind {
+        IssueKind::TempFile => !is_recent(&path),
+        IssueKind::OrphanHistory if path.is_dir() => !is_recent(&path) && snapshot_names(&path)?.is_empty(),
+        IssueKind::OrphanHistory => {
+            let snapshot = path.with_extension("");
+            path.exists() && !snapshot.exists() && !history::manifest_path(&snapshot).exists()
+        }
+        IssueKind::OrphanToken => path.exists() && !known_users(data_dir)?.contains(&username_of(&issue.path)),
+        _ => path.exists(),
+    })
+}
+
+/// Repairs one issue while holding the lock of the user's tabs file and the object store,
+/// so an online repair never touches a snapshot or temp file a sync is writing.
+fn repair(data_dir: &Path, issue: &Issue) -> Result<bool, std::io::Error> {
+    match issue.kind {
+        // takes the lock itself
+        IssueKind::CorruptTabs => return load_tabs(&issue.path).map(|_| true),
+        // takes the store lock itself
+        IssueKind::UnreferencedObject => return history::collect_garbage(&data_dir.join("history")).map(|_| true),
+        // snapshots in git are left to git tools
+        IssueKind::CorruptSnapshot if issue.path.contains('@') => return Ok(false),
+        IssueKind::DuplicateUuid | IssueKind::InvalidHistoryDir => return Ok(false),
+        _ => {}
+    }
+    let owner = username_of(&issue.path);
+    let lock = (!owner.is_empty()).then(|| lock_file(&format!("{}.json", owner)));
+    let _guard = lock.as_ref().map(|lock| lock.lock().unwrap());
+    let _store = history::lock_store();
+    if !still_applies(data_dir, issue)? {
+        return Ok(false);
+    }
+    let path = data_dir.join(&issue.path);
+    match issue.kind {
+        IssueKind::CorruptSnapshot => quarantine_snapshot(data_dir, &path)?,
+        _ => remove(&path)?,
+    }
+    Ok(true)
+}
+
+/// Checks the tabs files, the history and the tokens under `data_dir`, and with `repair`
+/// repairs what can be repaired. Every repair is recorded in the audit log. The locks only
+/// guard against this process, another process must not write `data_dir` during a repair.
+pub fn check(data_dir: &Path, repair_issues: bool) -> Result<FsckReport, std::io::Error> {
+    let mut report = FsckReport { repair: repair_issues, ..Default::default() };
+    scan_data_dir(data_dir, &mut report)?;
+    let history_dir = data_dir.join("history");
+    if history_dir.exists() {
+        scan_history_dir(data_dir, &history_dir, &mut report)?;
+    }
+    if !repair_issues {
+        return Ok(report);
+    }
+    for issue in report.issues.iter_mut() {
+        match repair(data_dir, issue) {
+            Ok(repaired) => issue.repaired = repaired,
+            Err(e) => error!("Error repairing {} {}: {}", issue.kind, issue.path, e),
+        }
+        if issue.repaired {
+            audit::record("fsck_repaired", &username_of(&issue.path), serde_json::json!({
+                "kind": issue.kind,
+                "path": issue.path,
+                "message": issue.message,
+            }));
+        }
+    }
+    Ok(report)
+}
+
+/// Checks the configured data directory.
+pub fn run(repair: bool) -> Result<FsckReport, std::io::Error> {
+    let data_dir = CONFIG_INSTANCE.load().settings.data_dir.clone();
+    check(Path::new(&data_dir), repair)
+}
+
+/// Runs the check on a blocking thread, for the admin endpoint.
+pub async fn run_online(repair: bool) -> Result<FsckReport, String> {
+    match tokio::task::spawn_blocking(move || run(repair)).await {
+        Ok(value) => value.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// test module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GROUP: &str = r#"{"_id":"1","uuid":"g1","color":"","expand":true,"pinned":false,"tabs":[
+        {"uuid":"t1","favIconUrl":"","pinned":false,"title":"a","url":"https://a.example.com"},
+        {"uuid":"t1","favIconUrl":"","pinned":false,"title":"b","url":"https://b.example.com"}],
+        "tags":[],"time":0,"title":"group","titleEditing":null,"updatedAt":0}"#;
+
+    #[test]
+    fn test_duplicate_uuids() {
+        let groups: Vec<TabGroup> = serde_json::from_str(&format!("[{},{}]", GROUP, GROUP)).unwrap();
+        assert_eq!(duplicate_uuids(&groups), vec!["tab t1", "group g1", "tab t1", "tab t1"]);
+        assert!(duplicate_uuids(&groups[..0]).is_empty());
+    }
+
+    #[test]
+    fn test_username_of() {
+        assert_eq!(username_of("alice.txt"), "alice");
+        assert_eq!(username_of("history/100/.alice.json.abc.tmp"), "alice");
+        assert_eq!(username_of("history/100/bob.json.meta"), "bob");
+        assert_eq!(username_of("history/git/alice.git@100"), "alice");
+        assert_eq!(username_of("history/300"), "");
+        assert_eq!(username_of("history/objects"), "");
+    }
+
+    #[test]
+    fn test_oidc_user_token_is_known() {
+        let data_dir = std::env::temp_dir().join(format!("bot-fsck-tokens-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        fs::create_dir_all(data_dir.join("oidc")).unwrap();
+        fs::write(data_dir.join("users.txt"), "alice,secret\n").unwrap();
+        fs::write(data_dir.join(OIDC_USERS_FILE), "bob@example.com\n").unwrap();
+        for user in ["alice", "bob@example.com", "carol"] {
+            fs::write(data_dir.join(format!("{}.txt", user)), "token").unwrap();
+        }
+        let mut report = FsckReport::default();
+        scan_data_dir(&data_dir, &mut report).unwrap();
+        let issues = report.issues.iter().map(|issue| (issue.kind, issue.path.as_str())).collect::<Vec<_>>();
+        assert_eq!(issues, vec![(IssueKind::OrphanToken, "carol.txt")]);
+        // signed in through OIDC meanwhile
+        fs::write(data_dir.join(OIDC_USERS_FILE), "bob@example.com\ncarol\n").unwrap();
+        assert!(!repair(&data_dir, &report.issues[0]).unwrap());
+        assert!(data_dir.join("carol.txt").exists());
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    fn age(path: &Path) {
+        let modified = std::time::SystemTime::now() - GRACE_PERIOD * 2;
+        fs::File::open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn test_check_history() {
+        let data_dir = std::env::temp_dir().join(format!("bot-fsck-{}", std::process::id()));
+        let history_dir = data_dir.join("history");
+        let _ = fs::remove_dir_all(&data_dir);
+        fs::create_dir_all(history_dir.join("100")).unwrap();
+        fs::write(history_dir.join("100").join("alice.json"), format!("[{}]", GROUP)).unwrap();
+        fs::write(history_dir.join("100").join("bob.json.meta"), "{}").unwrap();
+        fs::create_dir_all(history_dir.join("200")).unwrap();
+        fs::write(history_dir.join("200").join("alice.json"), "[{").unwrap();
+        fs::create_dir_all(history_dir.join("300")).unwrap();
+        fs::create_dir_all(history_dir.join("backup")).unwrap();
+        fs::write(history_dir.join("100").join(".alice.json.abc.tmp"), "[").unwrap();
+        age(&history_dir.join("100").join(".alice.json.abc.tmp"));
+        age(&history_dir.join("300"));
+        // a sync writing right now
+        fs::create_dir_all(history_dir.join("400")).unwrap();
+        fs::write(history_dir.join("100").join(".bob.json.manifest.abc.tmp"), "{").unwrap();
+
+        let mut report = FsckReport::default();
+        scan_history_dir(&data_dir, &history_dir, &mut report).unwrap();
+        let issues = report.issues.iter().map(|issue| (issue.kind, issue.path.as_str())).collect::<Vec<_>>();
+        assert_eq!(issues, vec![
+            (IssueKind::TempFile, "history/100/.alice.json.abc.tmp"),
+            (IssueKind::OrphanHistory, "history/100/bob.json.meta"),
+            (IssueKind::DuplicateUuid, "history/100/alice.json"),
+            (IssueKind::CorruptSnapshot, "history/200/alice.json"),
+            (IssueKind::OrphanHistory, "history/300"),
+            (IssueKind::InvalidHistoryDir, "history/backup"),
+        ]);
+        assert_eq!(report.snapshots, 2);
+
+        for issue in &report.issues {
+            repair(&data_dir, issue).unwrap();
+        }
+        assert!(data_dir.join("quarantine").join("history-200-alice.json").exists());
+        let mut report = FsckReport::default();
+        scan_history_dir(&data_dir, &history_dir, &mut report).unwrap();
+        let kinds = report.issues.iter().map(|issue| issue.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![IssueKind::DuplicateUuid, IssueKind::InvalidHistoryDir]);
+        assert!(history_dir.join("400").exists() && history_dir.join("100").join(".bob.json.manifest.abc.tmp").exists());
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
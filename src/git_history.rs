@@ -0,0 +1,392 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use gix::objs::tree::{Entry, EntryKind};
+use gix::refs::transaction::PreviousValue;
+use gix::refs::Target;
+use gix::ObjectId;
+use crate::history::SnapshotMeta;
+use crate::labels::Labels;
+use crate::retention::{RetentionReport, RetentionSettings, Snapshot};
+use crate::util::lock_file;
+
+// history/git/alice.git, a bare repository per user
+const GIT_DIR: &str = "git";
+// the only file in every commit
+const TABS_FILE: &str = "tabs.json";
+
+fn git_error(e: impl std::fmt::Display) -> Error {
+    Error::other(format!("git: {}", e))
+}
+
+/// `history/git/alice.git` for `alice.json`.
+pub fn repo_path(history_dir: &Path, filename: &str) -> PathBuf {
+    history_dir.join(GIT_DIR).join(format!("{}.git", filename.trim_end_matches(".json")))
+}
+
+fn open(history_dir: &Path, filename: &str) -> Result<Option<gix::Repository>, Error> {
+    let path = repo_path(history_dir, filename);
+    if !path.exists() {
+        return Ok(None);
+    }
+    gix::open(path).map(Some).map_err(git_error)
+}
+
+fn open_or_init(history_dir: &Path, filename: &str) -> Result<gix::Repository, Error> {
+    match open(history_dir, filename)? {
+        Some(repo) => Ok(repo),
+        None => {
+            fs::create_dir_all(repo_path(history_dir, filename))?;
+            gix::init_bare(repo_path(history_dir, filename)).map_err(git_error)
+        }
+    }
+}
+
+fn head_name(repo: &gix::Repository) -> Result<gix::refs::FullName, Error> {
+    repo.head_name().map_err(git_error)?.ok_or_else(|| git_error("detached HEAD"))
+}
+
+fn head_id(repo: &gix::Repository) -> Result<Option<ObjectId>, Error> {
+    Ok(repo.head().map_err(git_error)?.id().map(|id| id.detach()))
+}
+
+/// Pretty printed, so `git log -p` shows one line per field.
+fn pretty(contents: &str) -> String {
+    match serde_json::from_str::<serde_json::Value>(contents) {
+        Ok(value) => serde_json::to_string_pretty(&value).unwrap() + "\n",
+        Err(_) => contents.to_string(),
+    }
+}
+
+fn message(meta: &SnapshotMeta) -> String {
+    let device = match (&meta.user_agent, &meta.client_version) {
+        (Some(user_agent), Some(version)) => format!("{} ({})", user_agent, version),
+        (Some(user_agent), None) => user_agent.clone(),
+        (None, Some(version)) => format!("client {}", version),
+        (None, None) => String::from("unknown device"),
+    };
+    let mut message = format!("Sync from {}: {} groups, {} tabs\n\nUsername: {}\nHash: {}\nSize: {}\nGroups: {}\nTabs: {}\n",
+        device, meta.group_count, meta.tab_count, meta.username, meta.hash, meta.size, meta.group_count, meta.tab_count);
+    for (key, value) in [("Client-Ip", &meta.ip), ("Region", &meta.region), ("User-Agent", &meta.user_agent), ("Client-Version", &meta.client_version)] {
+        if let Some(value) = value {
+            message += &format!("{}: {}\n", key, value);
+        }
+    }
+    message
+}
+
+/// Reads the trailers written by `message` back, `None` for commits made by other tools.
+fn parse_message(message: &str, saved_at: i64) -> Option<SnapshotMeta> {
+    let trailers: HashMap<&str, &str> = message.lines().filter_map(|line| line.split_once(": ")).collect();
+    let text = |key: &str| trailers.get(key).map(|value| value.to_string());
+    Some(SnapshotMeta {
+        username: text("Username")?,
+        saved_at,
+        hash: text("Hash")?,
+        size: trailers.get("Size")?.parse().ok()?,
+        group_count: trailers.get("Groups")?.parse().ok()?,
+        tab_count: trailers.get("Tabs")?.parse().ok()?,
+        ip: text("Client-Ip"),
+        region: text("Region"),
+        user_agent: text("User-Agent"),
+        client_version: text("Client-Version"),
+    })
+}
+
+fn tabs_of(commit: &gix::Commit) -> Result<Vec<u8>, Error> {
+    let tree = commit.tree().map_err(git_error)?;
+    let entry = tree.find_entry(TABS_FILE).ok_or_else(|| git_error(format!("no {} in {}", TABS_FILE, commit.id())))?;
+    Ok(entry.object().map_err(git_error)?.data.clone())
+}
+
+/// Commits `contents` as the new state of `filename`. Returns the commit time, or the time of
+/// the head commit when it already holds the same tabs.
+pub fn commit(history_dir: &Path, filename: &str, contents: &str, meta: &SnapshotMeta) -> Result<i64, Error> {
+    let repo = open_or_init(history_dir, filename)?;
+    let contents = pretty(contents);
+    let parent = head_id(&repo)?;
+    let mut now = chrono::Utc::now().timestamp();
+    if let Some(parent) = parent {
+        let head = repo.find_commit(parent).map_err(git_error)?;
+        let head_time = head.time().map_err(git_error)?.seconds;
+        if tabs_of(&head)? == contents.as_bytes() {
+            return Ok(head_time);
+        }
+        // commit times identify snapshots, so they never repeat within a repository
+        now = now.max(head_time + 1);
+    }
+
+    let blob = repo.write_blob(contents.as_bytes()).map_err(git_error)?.detach();
+    let tree = gix::objs::Tree {
+        entries: vec![Entry { mode: EntryKind::Blob.into(), filename: TABS_FILE.into(), oid: blob }],
+    };
+    let tree = repo.write_object(&tree).map_err(git_error)?.detach();
+    let signature = gix::actor::Signature {
+        name: meta.username.as_str().into(),
+        email: format!("{}@better-one-tab", meta.username).into(),
+        time: gix::date::Time::new(now, 0),
+    };
+    let commit = gix::objs::Commit {
+        tree,
+        parents: parent.into_iter().collect(),
+        author: signature.clone(),
+        committer: signature,
+        encoding: None,
+        message: message(meta).into(),
+        extra_headers: Vec::new(),
+    };
+    let id = repo.write_object(&commit).map_err(git_error)?.detach();
+    let previous = match parent {
+        Some(parent) => PreviousValue::MustExistAndMatch(Target::Object(parent)),
+        None => PreviousValue::MustNotExist,
+    };
+    repo.reference(head_name(&repo)?, id, previous, "sync").map_err(git_error)?;
+    Ok(now)
+}
+
+/// Commits of the repository from the head back along first parents, newest first.
+fn commits(repo: &gix::Repository) -> Result<Vec<gix::Commit<'_>>, Error> {
+    let mut commits = Vec::new();
+    let mut next = head_id(repo)?;
+    while let Some(id) = next {
+        let commit = repo.find_commit(id).map_err(git_error)?;
+        next = commit.parent_ids().next().map(|id| id.detach());
+        commits.push(commit);
+    }
+    Ok(commits)
+}
+
+fn snapshot(commit: &gix::Commit, filename: &str, labels: &Labels) -> Result<Snapshot, Error> {
+    let timestamp = commit.time().map_err(git_error)?.seconds;
+    Ok(Snapshot {
+        timestamp,
+        filename: filename.to_string(),
+        size: tabs_of(commit)?.len() as u64,
+        label: labels.get(filename, timestamp).cloned(),
+        meta: parse_message(&commit.message_raw_sloppy().to_string(), timestamp),
+        objects: Vec::new(),
+    })
+}
+
+fn filenames(history_dir: &Path) -> Result<Vec<String>, Error> {
+    let git_dir = history_dir.join(GIT_DIR);
+    if !git_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut filenames = Vec::new();
+    for entry in fs::read_dir(git_dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if let Some(username) = name.strip_suffix(".git") {
+            filenames.push(format!("{}.json", username));
+        }
+    }
+    Ok(filenames)
+}
+
+/// Every commit of every repository, or of the one of `filename`, the head commits are the current tabs.
+pub fn list_snapshots(history_dir: &Path, labels: &Labels, filename: Option<&str>) -> Result<Vec<Snapshot>, Error> {
+    let filenames = match filename {
+        Some(value) => vec![value.to_string()],
+        None => filenames(history_dir)?,
+    };
+    let mut snapshots = Vec::new();
+    for filename in filenames {
+        if let Some(repo) = open(history_dir, &filename)? {
+            for commit in commits(&repo)? {
+                snapshots.push(snapshot(&commit, &filename, labels)?);
+            }
+        }
+    }
+    Ok(snapshots)
+}
+
+/// Tabs of the newest commit at `timestamp`.
+pub fn read_snapshot(history_dir: &Path, timestamp: i64, filename: &str) -> Result<Option<String>, Error> {
+    let repo = match open(history_dir, filename)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    for commit in commits(&repo)? {
+        if commit.time().map_err(git_error)?.seconds == timestamp {
+            let tabs = tabs_of(&commit)?;
+            return String::from_utf8(tabs).map(Some).map_err(git_error);
+        }
+    }
+    Ok(None)
+}
+
+/// Rewrites the first parent chain without the commits at `removed`, each removed commit is
+/// squashed into the next kept one. The head is always kept.
+fn squash(repo: &gix::Repository, removed: &HashSet<i64>) -> Result<(), Error> {
+    let commits = commits(repo)?;
+    let head = match commits.first() {
+        Some(value) => value.id,
+        None => return Ok(()),
+    };
+    let (mut parent, mut squashed, mut rewritten): (Option<ObjectId>, usize, bool) = (None, 0, false);
+    for (index, commit) in commits.iter().enumerate().rev() {
+        if index > 0 && removed.contains(&commit.time().map_err(git_error)?.seconds) {
+            squashed += 1;
+            continue;
+        }
+        if !rewritten && squashed == 0 {
+            // nothing changed below this commit yet
+            parent = Some(commit.id);
+            continue;
+        }
+        let mut owned = commit.decode().map_err(git_error)?.into_owned().map_err(git_error)?;
+        owned.parents = parent.into_iter().collect();
+        if squashed > 0 {
+            owned.message = format!("{}Squashed-Syncs: {}\n", owned.message, squashed).into();
+        }
+        parent = Some(repo.write_object(&owned).map_err(git_error)?.detach());
+        (squashed, rewritten) = (0, true);
+    }
+    if rewritten {
+        let previous = PreviousValue::MustExistAndMatch(Target::Object(head));
+        repo.reference(head_name(repo)?, parent.unwrap(), previous, "retention: squash").map_err(git_error)?;
+    }
+    Ok(())
+}
+
+/// Plans retention over the commits of all repositories, the head commits are the current tabs
+/// and not part of it, and unless `dry_run` squashes the removed commits away. Their objects stay
+/// until a `git gc`, so their size is reported as unreferenced, not freed.
+pub fn run_in(history_dir: &Path, settings: &RetentionSettings, labels: &Mutex<Labels>, now: i64, dry_run: bool) -> Result<RetentionReport, Error> {
+    // a copy, so labeling is not blocked during the walk
+    let planned_labels = labels.lock().unwrap().clone();
+    let mut snapshots = Vec::new();
+    let mut repos = Vec::new();
+    for filename in filenames(history_dir)? {
+        if let Some(repo) = open(history_dir, &filename)? {
+            for commit in commits(&repo)?.iter().skip(1) {
+                snapshots.push(snapshot(commit, &filename, &planned_labels)?);
+            }
+            repos.push((filename, repo));
+        }
+    }
+    let mut report = settings.plan(&snapshots, now);
+    report.dry_run = dry_run;
+    (report.bytes_freed, report.bytes_unreferenced) = (0, report.bytes_freed);
+    if dry_run {
+        return Ok(report);
+    }
+    for (filename, repo) in repos {
+        // commits of a sync move the same ref
+        let lock = lock_file(&filename);
+        let _file = lock.lock().unwrap();
+        let labels = labels.lock().unwrap();
+        // labeled since the plan was made
+        let count = report.removed.len();
+        report.removed.retain(|decision| decision.snapshot.filename != filename || labels.get(&filename, decision.snapshot.timestamp).is_none());
+        report.kept += count - report.removed.len();
+        let removed: HashSet<i64> = report.removed.iter()
+            .filter(|decision| decision.snapshot.filename == filename)
+            .map(|decision| decision.snapshot.timestamp)
+            .collect();
+        if !removed.is_empty() {
+            squash(&repo, &removed)?;
+        }
+    }
+    report.bytes_unreferenced = report.removed.iter().map(|decision| decision.snapshot.size).sum();
+    Ok(report)
+}
+
+// test module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history_dir(name: &str) -> PathBuf {
+        let history_dir = std::env::temp_dir().join(format!("bot-git-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&history_dir);
+        fs::create_dir_all(&history_dir).unwrap();
+        history_dir
+    }
+
+    fn tabs(title: &str) -> String {
+        format!(r#"[{{"_id":"1","uuid":"g1","color":"","expand":true,"pinned":false,"tabs":[
+            {{"uuid":"t1","favIconUrl":"","pinned":false,"title":"{}","url":"https://a.example.com"}}],
+            "tags":[],"time":0,"title":"group","titleEditing":null,"updatedAt":0}}]"#, title)
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let meta = SnapshotMeta {
+            user_agent: Some(String::from("Mozilla/5.0")),
+            client_version: Some(String::from("1.4.0")),
+            region: Some(String::from("SG")),
+            ..SnapshotMeta::new("alice", &tabs("a"))
+        };
+        let message = message(&meta);
+        assert!(message.starts_with("Sync from Mozilla/5.0 (1.4.0): 1 groups, 1 tabs\n"));
+        assert_eq!(parse_message(&message, meta.saved_at), Some(meta));
+        assert_eq!(parse_message("Initial commit\n", 0), None);
+    }
+
+    #[test]
+    fn test_commit_list_and_read() {
+        let history_dir = history_dir("commit");
+        let first = commit(&history_dir, "alice.json", &tabs("a"), &SnapshotMeta::new("alice", &tabs("a"))).unwrap();
+        // the same tabs make no commit
+        assert_eq!(commit(&history_dir, "alice.json", &tabs("a"), &SnapshotMeta::new("alice", &tabs("a"))).unwrap(), first);
+        // within the same second the next commit gets the next second
+        let second = commit(&history_dir, "alice.json", &tabs("b"), &SnapshotMeta::new("alice", &tabs("b"))).unwrap();
+        assert!(second > first);
+        let snapshots = list_snapshots(&history_dir, &Labels::new(), None).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].meta.as_ref().map(|meta| meta.tab_count), Some(1));
+
+        let contents = read_snapshot(&history_dir, first, "alice.json").unwrap().unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&contents).unwrap(), serde_json::from_str::<serde_json::Value>(&tabs("a")).unwrap());
+        assert_eq!(read_snapshot(&history_dir, second + 1, "alice.json").unwrap(), None);
+        assert_eq!(read_snapshot(&history_dir, first, "bob.json").unwrap(), None);
+        fs::remove_dir_all(&history_dir).unwrap();
+    }
+
+    #[test]
+    fn test_squash_keeps_head_and_state() {
+        let history_dir = history_dir("squash");
+        let repo = open_or_init(&history_dir, "alice.json").unwrap();
+        // commits one second apart, without waiting
+        let mut parent = None;
+        for (seconds, title) in [(100, "a"), (200, "b"), (300, "c"), (400, "d")] {
+            let blob = repo.write_blob(pretty(&tabs(title)).as_bytes()).unwrap().detach();
+            let tree = repo.write_object(&gix::objs::Tree {
+                entries: vec![Entry { mode: EntryKind::Blob.into(), filename: TABS_FILE.into(), oid: blob }],
+            }).unwrap().detach();
+            let signature = gix::actor::Signature { name: "alice".into(), email: "alice@better-one-tab".into(), time: gix::date::Time::new(seconds, 0) };
+            let commit = gix::objs::Commit {
+                tree,
+                parents: parent.into_iter().collect(),
+                author: signature.clone(),
+                committer: signature,
+                encoding: None,
+                message: message(&SnapshotMeta::new("alice", &tabs(title))).into(),
+                extra_headers: Vec::new(),
+            };
+            parent = Some(repo.write_object(&commit).unwrap().detach());
+        }
+        repo.reference(head_name(&repo).unwrap(), parent.unwrap(), PreviousValue::Any, "test").unwrap();
+
+        let settings = RetentionSettings { max_keep: Some(1), ..Default::default() };
+        let report = run_in(&history_dir, &settings, &Mutex::new(Labels::new()), 1000, true).unwrap();
+        // the head is the current tabs, of the other three the newest is kept
+        assert_eq!(report.removed.iter().map(|decision| decision.snapshot.timestamp).collect::<Vec<i64>>(), vec![200, 100]);
+        assert_eq!(list_snapshots(&history_dir, &Labels::new(), None).unwrap().len(), 4);
+
+        let report = run_in(&history_dir, &settings, &Mutex::new(Labels::new()), 1000, false).unwrap();
+        assert_eq!(report.bytes_freed, 0);
+        assert_eq!(report.bytes_unreferenced, report.removed.iter().map(|decision| decision.snapshot.size).sum::<u64>());
+        let snapshots = list_snapshots(&history_dir, &Labels::new(), None).unwrap();
+        assert_eq!(snapshots.iter().map(|snapshot| snapshot.timestamp).collect::<Vec<i64>>(), vec![400, 300]);
+        let contents = read_snapshot(&history_dir, 300, "alice.json").unwrap().unwrap();
+        assert!(contents.contains(r#""title": "c""#));
+        let repo = open(&history_dir, "alice.json").unwrap().unwrap();
+        let root = commits(&repo).unwrap().pop().unwrap();
+        assert!(root.message_raw_sloppy().to_string().ends_with("Squashed-Syncs: 2\n"));
+        fs::remove_dir_all(&history_dir).unwrap();
+    }
+}
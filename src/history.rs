@@ -0,0 +1,393 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use sha2::{Digest, Sha256};
+use crate::models::tabs::TabGroup;
+use crate::util::write_atomic;
+
+lazy_static! {
+    // objects are written before their manifest, garbage collection must not run in between
+    static ref STORE: Mutex<()> = Mutex::new(());
+}
+
+// sidecar of a tabs file, `alice.json.meta` next to `alice.json`
+pub const META_EXTENSION: &str = ".meta";
+// a snapshot in the object store, `history/<unix time>/alice.json.manifest`
+pub const MANIFEST_EXTENSION: &str = ".manifest";
+const OBJECTS_DIR: &str = "objects";
+const COMPRESSION_LEVEL: i32 = 10;
+
+/// What is known about a saved tabs document, kept next to the current file and every snapshot
+/// so listings do not have to parse the documents.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct SnapshotMeta {
+    pub username: String,
+    pub saved_at: i64,
+    // sha256 of the normalized document
+    pub hash: String,
+    pub size: u64,
+    pub group_count: usize,
+    pub tab_count: usize,
+    #[serde(default)]
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub region: Option<String>,
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    #[serde(default)]
+    pub client_version: Option<String>,
+}
+
+impl SnapshotMeta {
+    /// Describes `contents`, the client fields are left empty.
+    pub fn new(username: &str, contents: &str) -> Self {
+        let (group_count, tab_count) = match serde_json::from_str::<Vec<TabGroup>>(contents) {
+            Ok(groups) => (groups.len(), groups.iter().map(|group| group.tabs.len()).sum()),
+            Err(_) => (0, 0),
+        };
+        SnapshotMeta {
+            username: username.to_string(),
+            saved_at: chrono::Utc::now().timestamp(),
+            hash: normalized_hash(contents),
+            size: contents.len() as u64,
+            group_count,
+            tab_count,
+            ..Default::default()
+        }
+    }
+}
+
+/// sha256 of the document with sorted keys and no whitespace, so formatting does not count as a change.
+pub fn normalized_hash(contents: &str) -> String {
+    let normalized = match serde_json::from_str::<serde_json::Value>(contents) {
+        Ok(value) => value.to_string(),
+        Err(_) => contents.to_string(),
+    };
+    format!("{:x}", Sha256::digest(normalized.as_bytes()))
+}
+
+pub fn meta_path(path: &Path) -> PathBuf {
+    let mut meta_path = path.as_os_str().to_owned();
+    meta_path.push(META_EXTENSION);
+    PathBuf::from(meta_path)
+}
+
+pub fn read_meta(path: &Path) -> Option<SnapshotMeta> {
+    let contents = fs::read_to_string(meta_path(path)).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+pub fn write_meta(path: &Path, meta: &SnapshotMeta) -> Result<(), std::io::Error> {
+    write_atomic(&meta_path(path), serde_json::to_string_pretty(meta).unwrap().as_bytes())
+}
+
+/// A snapshot in the object store: the ids of its tab groups in order, or for a document
+/// that is not a list of groups the id of the whole document.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Manifest {
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub document: Option<String>,
+}
+
+impl Manifest {
+    pub fn objects(&self) -> impl Iterator<Item = &String> {
+        self.groups.iter().chain(self.document.iter())
+    }
+}
+
+pub fn manifest_path(path: &Path) -> PathBuf {
+    let mut manifest_path = path.as_os_str().to_owned();
+    manifest_path.push(MANIFEST_EXTENSION);
+    PathBuf::from(manifest_path)
+}
+
+/// `history/objects/ab/cdef....zst`, zstd compressed and named by the sha256 of the content.
+pub fn object_path(history_dir: &Path, id: &str) -> PathBuf {
+    history_dir.join(OBJECTS_DIR).join(&id[..2]).join(format!("{}.zst", &id[2..]))
+}
+
+fn write_object(history_dir: &Path, content: &str) -> Result<String, Error> {
+    let id = format!("{:x}", Sha256::digest(content.as_bytes()));
+    let path = object_path(history_dir, &id);
+    // stored once across all snapshots
+    if !path.exists() {
+        fs::create_dir_all(path.parent().unwrap())?;
+        write_atomic(&path, &zstd::encode_all(content.as_bytes(), COMPRESSION_LEVEL)?)?;
+    }
+    Ok(id)
+}
+
+fn read_object(history_dir: &Path, id: &str) -> Result<String, Error> {
+    if id.len() != 64 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Invalid object id {}", id)));
+    }
+    let bytes = zstd::decode_all(fs::File::open(object_path(history_dir, id))?)?;
+    String::from_utf8(bytes).map_err(|e| Error::new(ErrorKind::InvalidData, format!("Object {}: {}", id, e)))
+}
+
+pub fn read_manifest(path: &Path) -> Result<Manifest, Error> {
+    serde_json::from_str(&fs::read_to_string(path)?).map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+}
+
+/// Held to keep the object store from changing, e.g. while a backup links its files.
+pub fn lock_store() -> MutexGuard<'static, ()> {
+    STORE.lock().unwrap()
+}
+
+/// Stores `contents` as `filename` of the `history/<timestamp>/` snapshot, each tab group as an object.
+pub fn write_snapshot(history_dir: &Path, timestamp: i64, filename: &str, contents: &str) -> Result<(), Error> {
+    let _store = STORE.lock().unwrap();
+    let manifest = match serde_json::from_str::<Vec<&RawValue>>(contents) {
+        Ok(groups) => Manifest {
+            groups: groups.iter().map(|group| write_object(history_dir, group.get())).collect::<Result<_, _>>()?,
+            document: None,
+        },
+        Err(_) => Manifest { groups: Vec::new(), document: Some(write_object(history_dir, contents)?) },
+    };
+    let snapshot_dir = history_dir.join(timestamp.to_string());
+    fs::create_dir_all(&snapshot_dir)?;
+    write_atomic(&manifest_path(&snapshot_dir.join(filename)), serde_json::to_string(&manifest).unwrap().as_bytes())
+}
+
+/// Contents of a snapshot, from the object store or a plain copy taken before it existed.
+pub fn read_snapshot(history_dir: &Path, timestamp: i64, filename: &str) -> Result<String, Error> {
+    let path = history_dir.join(timestamp.to_string()).join(filename);
+    let manifest = match read_manifest(&manifest_path(&path)) {
+        Ok(manifest) => manifest,
+        Err(e) if e.kind() == ErrorKind::NotFound => return fs::read_to_string(&path),
+        Err(e) => return Err(e),
+    };
+    if let Some(id) = &manifest.document {
+        return read_object(history_dir, id);
+    }
+    let groups = manifest.groups.iter().map(|id| read_object(history_dir, id)).collect::<Result<Vec<_>, _>>()?;
+    Ok(format!("[{}]", groups.join(",")))
+}
+
+pub fn snapshot_exists(history_dir: &Path, timestamp: i64, filename: &str) -> bool {
+    let path = history_dir.join(timestamp.to_string()).join(filename);
+    manifest_path(&path).exists() || path.exists()
+}
+
+/// Stores `contents` as a snapshot at `now` with `meta` as its sidecar. When that second already
+/// has a different snapshot of the file, the next free second is used, like git commit times,
+/// and when it has the same content that snapshot is kept. Returns the snapshot time.
+pub fn snapshot(history_dir: &Path, now: i64, filename: &str, contents: &str, meta: Option<&SnapshotMeta>) -> Result<i64, Error> {
+    let hash = normalized_hash(contents);
+    let mut timestamp = now;
+    while snapshot_exists(history_dir, timestamp, filename) {
+        if read_snapshot(history_dir, timestamp, filename).is_ok_and(|existing| normalized_hash(&existing) == hash) {
+            return Ok(timestamp);
+        }
+        timestamp += 1;
+    }
+    write_snapshot(history_dir, timestamp, filename, contents)?;
+    if let Some(meta) = meta {
+        write_meta(&history_dir.join(timestamp.to_string()).join(filename), meta)?;
+    }
+    Ok(timestamp)
+}
+
+/// Removes a snapshot and its sidecar, objects stay until the next garbage collection.
+pub fn remove_snapshot(history_dir: &Path, timestamp: i64, filename: &str) -> Result<(), Error> {
+    let path = history_dir.join(timestamp.to_string()).join(filename);
+    for path in [manifest_path(&path), meta_path(&path), path] {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sizes of all stored objects by id.
+pub fn object_sizes(history_dir: &Path) -> Result<HashMap<String, u64>, Error> {
+    let mut sizes = HashMap::new();
+    let objects_dir = history_dir.join(OBJECTS_DIR);
+    if !objects_dir.exists() {
+        return Ok(sizes);
+    }
+    for prefix in fs::read_dir(objects_dir)? {
+        let prefix = prefix?;
+        for object in fs::read_dir(prefix.path())? {
+            let object = object?;
+            let name = object.file_name().to_string_lossy().into_owned();
+            if let Some(rest) = name.strip_suffix(".zst") {
+                sizes.insert(format!("{}{}", prefix.file_name().to_string_lossy(), rest), object.metadata()?.len());
+            }
+        }
+    }
+    Ok(sizes)
+}
+
+fn manifest_paths(history_dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(history_dir)? {
+        let path = entry?.path();
+        if !path.is_dir() || path.file_name().and_then(|name| name.to_str()).and_then(|name| name.parse::<i64>().ok()).is_none() {
+            continue;
+        }
+        for file in fs::read_dir(&path)? {
+            let file = file?.path();
+            if file.to_string_lossy().ends_with(MANIFEST_EXTENSION) {
+                paths.push(file);
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// Objects no snapshot refers to, with their sizes. Fails on a manifest that can not be read
+/// rather than count its objects as unreferenced.
+pub fn unreferenced_objects(history_dir: &Path) -> Result<HashMap<String, u64>, Error> {
+    let mut referenced = HashSet::new();
+    for path in manifest_paths(history_dir)? {
+        referenced.extend(read_manifest(&path)?.objects().cloned());
+    }
+    let mut sizes = object_sizes(history_dir)?;
+    sizes.retain(|id, _| !referenced.contains(id));
+    Ok(sizes)
+}
+
+/// Removes objects no snapshot refers to. Returns the freed bytes.
+pub fn collect_garbage(history_dir: &Path) -> Result<u64, Error> {
+    let _store = STORE.lock().unwrap();
+    let mut freed = 0;
+    for (id, size) in unreferenced_objects(history_dir)? {
+        fs::remove_file(object_path(history_dir, &id))?;
+        freed += size;
+    }
+    Ok(freed)
+}
+
+/// Moves plain snapshot copies into the object store. Returns the number of converted snapshots.
+pub fn compact(history_dir: &Path) -> Result<usize, Error> {
+    let mut converted = 0;
+    for entry in fs::read_dir(history_dir)? {
+        let path = entry?.path();
+        let timestamp = match path.file_name().and_then(|name| name.to_str()).and_then(|name| name.parse::<i64>().ok()) {
+            Some(value) if path.is_dir() => value,
+            _ => continue,
+        };
+        for file in fs::read_dir(&path)? {
+            let file = file?.path();
+            let filename = file.file_name().unwrap().to_string_lossy().into_owned();
+            if filename.ends_with(META_EXTENSION) || filename.ends_with(MANIFEST_EXTENSION) || filename.starts_with('.') {
+                continue;
+            }
+            write_snapshot(history_dir, timestamp, &filename, &fs::read_to_string(&file)?)?;
+            fs::remove_file(&file)?;
+            converted += 1;
+        }
+    }
+    Ok(converted)
+}
+
+// test module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABS: &str = r#"[{"_id":"1","uuid":"g1","color":"","expand":true,"pinned":false,"tabs":[
+        {"uuid":"t1","favIconUrl":"","pinned":false,"title":"a","url":"https://a.example.com"},
+        {"uuid":"t2","favIconUrl":"","pinned":false,"title":"b","url":"https://b.example.com"}],
+        "tags":[],"time":0,"title":"group","titleEditing":null,"updatedAt":0}]"#;
+
+    #[test]
+    fn test_normalized_hash_ignores_formatting() {
+        assert_eq!(normalized_hash(r#"{"a": 1, "b": [1, 2]}"#), normalized_hash(r#"{"b":[1,2],"a":1}"#));
+        assert_ne!(normalized_hash(r#"{"a": 1}"#), normalized_hash(r#"{"a": 2}"#));
+    }
+
+    #[test]
+    fn test_new_counts_groups_and_tabs() {
+        let meta = SnapshotMeta::new("alice", TABS);
+        assert_eq!(meta.group_count, 1);
+        assert_eq!(meta.tab_count, 2);
+        assert_eq!(meta.size, TABS.len() as u64);
+        assert_eq!(meta.hash.len(), 64);
+    }
+
+    #[test]
+    fn test_write_and_read_meta() {
+        let path = std::env::temp_dir().join(format!("bot-meta-{}.json", std::process::id()));
+        assert_eq!(read_meta(&path), None);
+        let meta = SnapshotMeta { client_version: Some(String::from("1.2.0")), ..SnapshotMeta::new("alice", TABS) };
+        write_meta(&path, &meta).unwrap();
+        assert_eq!(read_meta(&path), Some(meta));
+        fs::remove_file(meta_path(&path)).unwrap();
+    }
+
+    fn history_dir(name: &str) -> PathBuf {
+        let history_dir = std::env::temp_dir().join(format!("bot-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&history_dir);
+        fs::create_dir_all(&history_dir).unwrap();
+        history_dir
+    }
+
+    #[test]
+    fn test_snapshots_share_groups() {
+        let history_dir = history_dir("share");
+        let other = TABS.replace(r#""title":"group""#, r#""title":"other""#);
+        let both = format!("[{},{}]", &TABS[1..TABS.len() - 1], &other[1..other.len() - 1]);
+        write_snapshot(&history_dir, 100, "alice.json", TABS).unwrap();
+        write_snapshot(&history_dir, 200, "alice.json", &both).unwrap();
+        write_snapshot(&history_dir, 300, "alice.json", "{\"not\": \"groups\"}").unwrap();
+
+        assert_eq!(read_snapshot(&history_dir, 100, "alice.json").unwrap(), TABS);
+        assert_eq!(read_snapshot(&history_dir, 200, "alice.json").unwrap(), both);
+        assert_eq!(read_snapshot(&history_dir, 300, "alice.json").unwrap(), "{\"not\": \"groups\"}");
+        // the first group is stored once
+        assert_eq!(object_sizes(&history_dir).unwrap().len(), 3);
+
+        remove_snapshot(&history_dir, 200, "alice.json").unwrap();
+        assert!(!snapshot_exists(&history_dir, 200, "alice.json"));
+        assert!(collect_garbage(&history_dir).unwrap() > 0);
+        assert_eq!(object_sizes(&history_dir).unwrap().len(), 2);
+        assert_eq!(read_snapshot(&history_dir, 100, "alice.json").unwrap(), TABS);
+        fs::remove_dir_all(&history_dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_in_the_same_second() {
+        let history_dir = history_dir("second");
+        let other = TABS.replace(r#""title":"group""#, r#""title":"other""#);
+        assert_eq!(snapshot(&history_dir, 100, "alice.json", TABS, None).unwrap(), 100);
+        // a different content moves to the next second, the same content is kept
+        let meta = SnapshotMeta::new("alice", &other);
+        assert_eq!(snapshot(&history_dir, 100, "alice.json", &other, Some(&meta)).unwrap(), 101);
+        assert_eq!(snapshot(&history_dir, 100, "alice.json", &other, None).unwrap(), 101);
+        assert_eq!(snapshot(&history_dir, 100, "bob.json", &other, None).unwrap(), 100);
+        assert_eq!(read_snapshot(&history_dir, 100, "alice.json").unwrap(), TABS);
+        assert_eq!(read_snapshot(&history_dir, 101, "alice.json").unwrap(), other);
+        assert_eq!(read_meta(&history_dir.join("101").join("alice.json")), Some(meta));
+        fs::remove_dir_all(&history_dir).unwrap();
+    }
+
+    #[test]
+    fn test_plain_snapshots_and_compact() {
+        let history_dir = history_dir("compact");
+        fs::create_dir_all(history_dir.join("100")).unwrap();
+        fs::write(history_dir.join("100").join("alice.json"), TABS).unwrap();
+        fs::write(history_dir.join("100").join("alice.json.meta"), "{}").unwrap();
+        assert!(snapshot_exists(&history_dir, 100, "alice.json"));
+        assert_eq!(read_snapshot(&history_dir, 100, "alice.json").unwrap(), TABS);
+
+        assert_eq!(compact(&history_dir).unwrap(), 1);
+        assert!(!history_dir.join("100").join("alice.json").exists());
+        assert!(history_dir.join("100").join("alice.json.meta").exists());
+        assert_eq!(read_snapshot(&history_dir, 100, "alice.json").unwrap(), TABS);
+        assert_eq!(compact(&history_dir).unwrap(), 0);
+        fs::remove_dir_all(&history_dir).unwrap();
+    }
+
+    #[test]
+    fn test_meta_path() {
+        assert_eq!(meta_path(Path::new("./data/alice.json")), PathBuf::from("./data/alice.json.meta"));
+    }
+}
@@ -1,55 +1,385 @@
-use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
 use std::fs;
-use std::sync::Mutex;
-use log::{error, info};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use arc_swap::ArcSwap;
+use ipnet::IpNet;
+use log::{error, info, warn};
+use maxminddb::{geoip2, Reader};
+use crate::config::Settings;
 
-pub struct Ip  {
-    pub low: u32,
-    pub high: u32,
+pub struct Ip<K>  {
+    pub low: K,
+    pub high: K,
     pub region: String,
 }
 
+/// Numeric form of an address family, bounds in the csv are either numbers or address strings.
+pub trait RangeKey: Copy + Ord {
+    fn parse_bound(value: &str) -> Option<Self>;
+}
+
+impl RangeKey for u32 {
+    fn parse_bound(value: &str) -> Option<Self> {
+        value.parse::<u32>().ok()
+            .or_else(|| value.parse::<Ipv4Addr>().ok().map(u32::from))
+    }
+}
+
+impl RangeKey for u128 {
+    fn parse_bound(value: &str) -> Option<Self> {
+        value.parse::<u128>().ok()
+            .or_else(|| value.parse::<Ipv6Addr>().ok().map(u128::from))
+    }
+}
+
+/// Ip ranges sorted by `low`, stored column-wise so the binary search only walks `lows`.
+/// Regions (or any other value, like the AS number) are interned, every range keeps an index into `regions`.
+pub struct RangeTable<K> {
+    lows: Vec<K>,
+    highs: Vec<K>,
+    region_indexes: Vec<u32>,
+    regions: Vec<String>,
+}
+
+impl<K: RangeKey> RangeTable<K> {
+    pub fn from_ranges(mut ip_list: Vec<Ip<K>>) -> Self {
+        ip_list.sort_unstable_by_key(|ip| ip.low);
+        let mut table = Self {
+            lows: Vec::with_capacity(ip_list.len()),
+            highs: Vec::with_capacity(ip_list.len()),
+            region_indexes: Vec::with_capacity(ip_list.len()),
+            regions: Vec::new(),
+        };
+        let mut region_index_map: HashMap<String, u32> = HashMap::new();
+        for ip in ip_list {
+            let next_index = table.regions.len() as u32;
+            let region_index = *region_index_map.entry(ip.region.clone()).or_insert_with(|| {
+                table.regions.push(ip.region);
+                next_index
+            });
+            table.lows.push(ip.low);
+            table.highs.push(ip.high);
+            table.region_indexes.push(region_index);
+        }
+        table
+    }
+
+    pub fn len(&self) -> usize {
+        self.lows.len()
+    }
+
+    /// Checks every range is well formed, sorted and does not overlap the next one.
+    pub fn validate(&self) -> Result<(), String> {
+        for i in 0..self.lows.len() {
+            if self.lows[i] > self.highs[i] {
+                return Err(format!("range {} ends before it starts", i));
+            }
+            if i > 0 && self.highs[i - 1] >= self.lows[i] {
+                return Err(format!("range {} overlaps range {}", i - 1, i));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_region(&self, ip: K) -> Option<&str> {
+        // index of the first range starting after ip, the candidate is the one before it
+        let (mut left, mut right) = (0, self.lows.len());
+        while left < right {
+            let middle = (left + right) / 2;
+            if self.lows[middle] <= ip {
+                left = middle + 1;
+            } else {
+                right = middle;
+            }
+        }
+        if left > 0 && ip <= self.highs[left - 1] {
+            return Some(&self.regions[self.region_indexes[left - 1] as usize]);
+        }
+        None
+    }
+}
+
+/// Country or ASN database, csv ranges plus MaxMind DB files.
 pub struct Ips {
-    pub ip_list: Vec<Ip>
+    pub ipv4: RangeTable<u32>,
+    pub ipv6: RangeTable<u128>,
+    // consulted after the csv tables, in the configured order
+    pub mmdb: Vec<Reader<Vec<u8>>>,
 }
 
 impl Ips{
     pub fn new() -> Self {
-        let contents = match fs::read_to_string("./config/dbip-country-ipv4-num.csv"){
-            Ok(value) => value,
-            Err(_) => {
-                error!("Failed to read ip list file");
-                return Self {
-                    ip_list: Vec::new()
+        Self::load(&Settings::new().geoip_files)
+    }
+
+    /// Loads every file by extension: `.mmdb` as MaxMind DB, anything else as csv.
+    pub fn load(files: &[String]) -> Self {
+        let mut ipv4_list: Vec<Ip<u32>> = Vec::new();
+        let mut ipv6_list: Vec<Ip<u128>> = Vec::new();
+        let mut mmdb = Vec::new();
+        for filename in files {
+            if Path::new(filename).extension().is_some_and(|extension| extension.eq_ignore_ascii_case("mmdb")) {
+                match Reader::open_readfile(filename) {
+                    Ok(reader) => {
+                        info!("Loaded {} ({}, ipv{}) from {}", reader.metadata.database_type, reader.metadata.node_count, reader.metadata.ip_version, filename);
+                        mmdb.push(reader);
+                    }
+                    Err(e) => error!("Failed to read mmdb file {}: {}", filename, e),
                 }
-            }
-        };
-        let lines = contents.split("\r\n").collect::<Vec<&str>>();
-        let mut ip_list: Vec<Ip> = Vec::new();
-        for line in lines {
-            let parts = line.split(",").collect::<Vec<&str>>();
-            if parts.len() != 3 {
                 continue;
             }
-            ip_list.push(Ip {
-                low: parts[0].parse::<u32>().unwrap(),
-                high: parts[1].parse::<u32>().unwrap(),
-                region: String::from(parts[2]),
-            });
+            match fs::read_to_string(filename) {
+                Ok(contents) => {
+                    let (ipv4_count, ipv6_count) = (ipv4_list.len(), ipv6_list.len());
+                    parse_csv(&contents, &mut ipv4_list, &mut ipv6_list);
+                    info!("Loaded {} ipv4 and {} ipv6 regions from {}", ipv4_list.len() - ipv4_count, ipv6_list.len() - ipv6_count, filename);
+                }
+                Err(_) => error!("Failed to read ip list file {}", filename),
+            }
         }
-        info!("Loaded {} ip regions", ip_list.len());
         Self {
-            ip_list
+            ipv4: RangeTable::from_ranges(ipv4_list),
+            ipv6: RangeTable::from_ranges(ipv6_list),
+            mmdb,
         }
     }
 
-    pub fn get_region(&self, ip: u32) -> String {
-        for i in 0..self.ip_list.len() {
-            if ip >= self.ip_list[i].low && ip <= self.ip_list[i].high {
-                return self.ip_list[i].region.clone();
+    pub fn from_csv(contents: &str) -> Self {
+        let mut ipv4_list: Vec<Ip<u32>> = Vec::new();
+        let mut ipv6_list: Vec<Ip<u128>> = Vec::new();
+        parse_csv(contents, &mut ipv4_list, &mut ipv6_list);
+        Self {
+            ipv4: RangeTable::from_ranges(ipv4_list),
+            ipv6: RangeTable::from_ranges(ipv6_list),
+            mmdb: Vec::new(),
+        }
+    }
+
+    fn get_table_value(&self, ip: IpAddr) -> Option<&str> {
+        match ip {
+            IpAddr::V4(v4) => self.ipv4.get_region(u32::from(v4)),
+            IpAddr::V6(v6) => self.ipv6.get_region(u128::from(v6)),
+        }
+    }
+
+    pub fn get_region(&self, ip: IpAddr) -> &str {
+        let ip = canonical(ip);
+        self.get_table_value(ip)
+            .or_else(|| self.mmdb.iter().find_map(|reader| mmdb_country(reader, ip)))
+            .unwrap_or("unknown")
+    }
+
+    /// For ASN databases, the csv value is the AS number.
+    pub fn get_asn(&self, ip: IpAddr) -> Option<u32> {
+        let ip = canonical(ip);
+        match self.get_table_value(ip) {
+            Some(value) => value.trim_start_matches("AS").parse::<u32>().ok(),
+            None => self.mmdb.iter().find_map(|reader| {
+                let asn: geoip2::Asn = reader.lookup(ip).ok()?;
+                asn.autonomous_system_number
+            }),
+        }
+    }
+}
+
+impl Reloadable for Ips {
+    fn range_count(&self) -> usize {
+        self.ipv4.len() + self.ipv6.len()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.range_count() == 0 && self.mmdb.is_empty() {
+            return Err("no ip ranges loaded".to_string());
+        }
+        self.ipv4.validate().map_err(|e| format!("ipv4 {}", e))?;
+        self.ipv6.validate().map_err(|e| format!("ipv6 {}", e))?;
+        Ok(())
+    }
+}
+
+/// Named local ip lists, like tor exit nodes or hosting provider ranges.
+/// One ip or CIDR per line, `#` starts a comment.
+pub struct IpLists {
+    lists: Vec<(String, Ips)>,
+}
+
+impl IpLists {
+    pub fn load(files: &HashMap<String, String>) -> Self {
+        let mut lists = Vec::new();
+        for (name, filename) in files {
+            match fs::read_to_string(filename) {
+                Ok(contents) => {
+                    let ips = Self::parse(name, &contents);
+                    info!("Loaded {} ranges of ip list {} from {}", ips.range_count(), name, filename);
+                    lists.push((name.clone(), ips));
+                }
+                Err(_) => error!("Failed to read ip list {} file {}", name, filename),
+            }
+        }
+        Self { lists }
+    }
+
+    pub fn parse(name: &str, contents: &str) -> Ips {
+        let mut ipv4_ranges: Vec<(u32, u32)> = Vec::new();
+        let mut ipv6_ranges: Vec<(u128, u128)> = Vec::new();
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
             }
+            let net = match line.parse::<IpNet>() {
+                Ok(value) => value,
+                Err(_) => match line.parse::<IpAddr>() {
+                    Ok(ip) => IpNet::from(ip),
+                    Err(_) => {
+                        warn!("Skip invalid line in ip list {}: {}", name, line);
+                        continue;
+                    }
+                },
+            };
+            match net {
+                IpNet::V4(net) => ipv4_ranges.push((u32::from(net.network()), u32::from(net.broadcast()))),
+                IpNet::V6(net) => ipv6_ranges.push((u128::from(net.network()), u128::from(net.broadcast()))),
+            }
+        }
+        Ips {
+            ipv4: RangeTable::from_ranges(merge_ranges(ipv4_ranges).into_iter().map(|(low, high)| Ip { low, high, region: name.to_string() }).collect()),
+            ipv6: RangeTable::from_ranges(merge_ranges(ipv6_ranges).into_iter().map(|(low, high)| Ip { low, high, region: name.to_string() }).collect()),
+            mmdb: Vec::new(),
+        }
+    }
+
+    /// Names of the lists containing the ip.
+    pub fn lists_containing(&self, ip: IpAddr) -> Vec<String> {
+        let ip = canonical(ip);
+        self.lists.iter()
+            .filter(|(_, ips)| ips.get_table_value(ip).is_some())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
+
+impl Reloadable for IpLists {
+    fn range_count(&self) -> usize {
+        self.lists.iter().map(|(_, ips)| ips.range_count()).sum()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (name, ips) in &self.lists {
+            ips.ipv4.validate().map_err(|e| format!("ip list {} ipv4 {}", name, e))?;
+            ips.ipv6.validate().map_err(|e| format!("ip list {} ipv6 {}", name, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Overlapping CIDRs of a list are merged, lookups need disjoint ranges.
+fn merge_ranges<K: RangeKey>(mut ranges: Vec<(K, K)>) -> Vec<(K, K)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(K, K)> = Vec::with_capacity(ranges.len());
+    for (low, high) in ranges {
+        match merged.last_mut() {
+            Some(last) if low <= last.1 => last.1 = last.1.max(high),
+            _ => merged.push((low, high)),
+        }
+    }
+    merged
+}
+
+/// Maps an ipv4-mapped ipv6 address to the ipv4 address, so both forms match the same ranges.
+pub fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Data that is loaded from files and swapped in as a whole.
+pub trait Reloadable: Send + Sync + 'static {
+    fn range_count(&self) -> usize;
+    fn validate(&self) -> Result<(), String>;
+}
+
+/// Builds a new table off the async runtime and swaps it in only when it validates,
+/// otherwise the current table stays in use.
+pub async fn reload<T, F>(instance: &ArcSwap<T>, name: &str, load: F) -> Result<usize, String>
+where
+    T: Reloadable,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let table = match tokio::task::spawn_blocking(load).await {
+        Ok(value) => value,
+        Err(e) => return Err(format!("Failed to load {} files: {}", name, e)),
+    };
+    if let Err(e) = table.validate() {
+        error!("Keep current {} table, new one is invalid: {}", name, e);
+        return Err(e);
+    }
+    let range_count = table.range_count();
+    info!("Reloaded {} table with {} ranges", name, range_count);
+    instance.store(Arc::new(table));
+    Ok(range_count)
+}
+
+fn modified_times(files: &[String]) -> Vec<Option<SystemTime>> {
+    files.iter()
+        .map(|filename| fs::metadata(filename).and_then(|metadata| metadata.modified()).ok())
+        .collect()
+}
+
+/// Polls the modification time of the files and calls `on_change` when one of them changes.
+pub async fn watch<F, Fut>(files: Vec<String>, interval_seconds: u64, on_change: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut last_modified = modified_times(&files);
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_seconds));
+    loop {
+        interval.tick().await;
+        let modified = modified_times(&files);
+        if modified != last_modified {
+            let changed = files.iter().zip(modified.iter().zip(&last_modified))
+                .filter(|(_, (now, before))| now != before)
+                .map(|(filename, _)| filename.as_str())
+                .collect::<Vec<&str>>();
+            info!("{} changed, reloading", changed.join(", "));
+            on_change().await;
+            last_modified = modified;
+        }
+    }
+}
+
+fn mmdb_country(reader: &Reader<Vec<u8>>, ip: IpAddr) -> Option<&str> {
+    let country: geoip2::Country = reader.lookup(ip).ok()?;
+    country.country.and_then(|country| country.iso_code)
+        .or_else(|| country.registered_country.and_then(|country| country.iso_code))
+}
+
+/// `low,high,value[,...]`, sorts every line into the ipv4 or ipv6 list, so one file may hold both families.
+/// Numeric bounds above `u32::MAX` are taken as ipv6.
+fn parse_csv(contents: &str, ipv4_list: &mut Vec<Ip<u32>>, ipv6_list: &mut Vec<Ip<u128>>) {
+    for line in contents.lines() {
+        // asn csv has the organization as 4th column, which may contain commas
+        let parts = line.splitn(4, ",").collect::<Vec<&str>>();
+        if parts.len() < 3 {
+            continue;
+        }
+        let (low, high, region) = (parts[0].trim(), parts[1].trim(), String::from(parts[2].trim()));
+        if let (Some(low), Some(high)) = (u32::parse_bound(low), u32::parse_bound(high)) {
+            ipv4_list.push(Ip { low, high, region });
+        } else if let (Some(low), Some(high)) = (u128::parse_bound(low), u128::parse_bound(high)) {
+            ipv6_list.push(Ip { low, high, region });
+        } else {
+            warn!("Skip invalid ip range line: {}", line);
         }
-        String::from("unknown")
     }
 }
 
@@ -59,15 +389,184 @@ mod tests {
     lazy_static! {
         pub static ref IPS_INSTANCE: Mutex<Ips> = Mutex::new(Ips::new());
     }
+    use std::hint::black_box;
+    use std::sync::Mutex;
+    use std::time::Instant;
+    use rand::Rng;
     use super::*;
 
+    fn ipv4(ip: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(ip))
+    }
+
     #[test]
     fn test_es() {
-        assert_eq!(IPS_INSTANCE.lock().unwrap().get_region(37347328), "ES");
+        assert_eq!(IPS_INSTANCE.lock().unwrap().get_region(ipv4(37347328)), "ES");
     }
 
     #[test]
     fn test_sg() {
-        assert_eq!(IPS_INSTANCE.lock().unwrap().get_region(37459967), "SG");
+        assert_eq!(IPS_INSTANCE.lock().unwrap().get_region(ipv4(37459967)), "SG");
+    }
+
+    #[test]
+    fn test_unsorted_csv() {
+        let table = Ips::from_csv("300,399,SG\r\n100,199,ES\r\nlow,high,region\r\n200,299,MY\r\n").ipv4;
+        assert_eq!(table.get_region(99), None);
+        assert_eq!(table.get_region(100), Some("ES"));
+        assert_eq!(table.get_region(199), Some("ES"));
+        assert_eq!(table.get_region(250), Some("MY"));
+        assert_eq!(table.get_region(399), Some("SG"));
+        assert_eq!(table.get_region(400), None);
+    }
+
+    #[test]
+    fn test_gap_between_ranges() {
+        let table = Ips::from_csv("100,199,ES\n300,399,SG\n").ipv4;
+        assert_eq!(table.get_region(250), None);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_ipv6() {
+        let ips = Ips::from_csv("1.0.0.0,1.0.0.255,AU\n2001:200::,2001:200:ffff:ffff:ffff:ffff:ffff:ffff,JP\n");
+        assert_eq!(ips.get_region("2001:200::1".parse().unwrap()), "JP");
+        assert_eq!(ips.get_region("2001:201::1".parse().unwrap()), "unknown");
+        assert_eq!(ips.get_region("1.0.0.7".parse().unwrap()), "AU");
+        // ipv4-mapped addresses are looked up in the ipv4 table
+        assert_eq!(ips.get_region("::ffff:1.0.0.7".parse().unwrap()), "AU");
+    }
+
+    fn mmdb_string(value: &str) -> Vec<u8> {
+        let mut bytes = vec![0x40 | value.len() as u8];
+        bytes.extend_from_slice(value.as_bytes());
+        bytes
+    }
+
+    fn mmdb_u16(value: u8) -> Vec<u8> {
+        vec![0xA1, value]
+    }
+
+    /// Writes a minimal ipv4 MaxMind DB (24 bit records) mapping one prefix to a country.
+    fn build_mmdb(prefix: Ipv4Addr, prefix_len: u32, iso_code: &str) -> Vec<u8> {
+        let node_count = prefix_len;
+        let prefix = u32::from(prefix);
+        let mut bytes = Vec::new();
+        for depth in 0..prefix_len {
+            let bit = (prefix >> (31 - depth)) & 1;
+            // the last node on the path points at the data section, offset 0
+            let on_path = if depth + 1 == prefix_len { node_count + 16 } else { depth + 1 };
+            let records = if bit == 0 { [on_path, node_count] } else { [node_count, on_path] };
+            for record in records {
+                bytes.extend_from_slice(&record.to_be_bytes()[1..]);
+            }
+        }
+        bytes.extend_from_slice(&[0; 16]);
+        // {"country": {"iso_code": iso_code}}
+        bytes.push(0xE1);
+        bytes.extend(mmdb_string("country"));
+        bytes.push(0xE1);
+        bytes.extend(mmdb_string("iso_code"));
+        bytes.extend(mmdb_string(iso_code));
+
+        bytes.extend_from_slice(b"\xAB\xCD\xEFMaxMind.com");
+        bytes.push(0xE9);
+        bytes.extend(mmdb_string("node_count"));
+        bytes.extend([0xC1, node_count as u8]);
+        bytes.extend(mmdb_string("record_size"));
+        bytes.extend(mmdb_u16(24));
+        bytes.extend(mmdb_string("ip_version"));
+        bytes.extend(mmdb_u16(4));
+        bytes.extend(mmdb_string("database_type"));
+        bytes.extend(mmdb_string("Test-Country"));
+        bytes.extend(mmdb_string("languages"));
+        bytes.extend([0x00, 0x04]);
+        bytes.extend(mmdb_string("description"));
+        bytes.push(0xE0);
+        bytes.extend(mmdb_string("binary_format_major_version"));
+        bytes.extend(mmdb_u16(2));
+        bytes.extend(mmdb_string("binary_format_minor_version"));
+        bytes.extend(mmdb_u16(0));
+        bytes.extend(mmdb_string("build_epoch"));
+        bytes.extend([0x01, 0x02, 1]);
+        bytes
+    }
+
+    #[test]
+    fn test_mmdb() {
+        let filename = std::env::temp_dir().join(format!("test-country-{}.mmdb", std::process::id()));
+        fs::write(&filename, build_mmdb(Ipv4Addr::new(1, 0, 0, 0), 24, "AU")).unwrap();
+        let ips = Ips::load(&[filename.to_str().unwrap().to_string()]);
+        fs::remove_file(&filename).unwrap();
+
+        assert_eq!(ips.mmdb.len(), 1);
+        assert_eq!(ips.get_region("1.0.0.7".parse().unwrap()), "AU");
+        assert_eq!(ips.get_region("::ffff:1.0.0.7".parse().unwrap()), "AU");
+        assert_eq!(ips.get_region("1.0.1.7".parse().unwrap()), "unknown");
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Ips::from_csv("100,199,ES\n200,299,SG\n").validate().is_ok());
+        assert!(Ips::from_csv("100,199,ES\n150,299,SG\n").validate().is_err());
+        assert!(Ips::from_csv("299,200,SG\n").validate().is_err());
+        assert!(Ips::from_csv("").validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_table_on_invalid_file() {
+        let filename = std::env::temp_dir().join(format!("test-reload-{}.csv", std::process::id()));
+        let files = vec![filename.to_str().unwrap().to_string()];
+        let instance = ArcSwap::from_pointee(Ips::from_csv("100,199,ES\n"));
+
+        fs::write(&filename, "100,199,SG\n200,299,MY\n").unwrap();
+        let load_files = files.clone();
+        assert_eq!(reload(&instance, "GeoIP", move || Ips::load(&load_files)).await, Ok(2));
+        assert_eq!(instance.load().get_region(ipv4(150)), "SG");
+
+        fs::write(&filename, "100,199,ES\n150,299,MY\n").unwrap();
+        assert!(reload(&instance, "GeoIP", move || Ips::load(&files)).await.is_err());
+        assert_eq!(instance.load().get_region(ipv4(150)), "SG");
+        fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn test_asn_csv() {
+        let asns = Ips::from_csv("1.0.0.0,1.0.0.255,13335,Cloudflare, Inc.\n2001:200::,2001:200:ffff::,2500,WIDE Project\n");
+        assert_eq!(asns.get_asn("1.0.0.1".parse().unwrap()), Some(13335));
+        assert_eq!(asns.get_asn("2001:200::1".parse().unwrap()), Some(2500));
+        assert_eq!(asns.get_asn("1.0.1.1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_ip_lists() {
+        let tor = IpLists::parse("tor", "# exit nodes\n198.51.100.7\n203.0.113.0/24\n203.0.113.128/25 # inside the /24\n2001:db8::/32\nnot an ip\n");
+        assert_eq!(tor.range_count(), 3);
+        let ip_lists = IpLists { lists: vec![("tor".to_string(), tor), ("hosting".to_string(), IpLists::parse("hosting", "203.0.113.200\n"))] };
+        assert!(ip_lists.validate().is_ok());
+        assert_eq!(ip_lists.lists_containing("198.51.100.7".parse().unwrap()), vec!["tor".to_string()]);
+        assert_eq!(ip_lists.lists_containing("203.0.113.200".parse().unwrap()), vec!["tor".to_string(), "hosting".to_string()]);
+        assert_eq!(ip_lists.lists_containing("::ffff:198.51.100.7".parse().unwrap()), vec!["tor".to_string()]);
+        assert_eq!(ip_lists.lists_containing("2001:db8::1".parse().unwrap()), vec!["tor".to_string()]);
+        assert!(ip_lists.lists_containing("198.51.100.8".parse().unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_lookup_speed() {
+        // about the size of the dbip country table
+        let range_count: u32 = 500_000;
+        let step = u32::MAX / range_count;
+        let ip_list = (0..range_count)
+            .map(|i| Ip { low: i * step, high: i * step + step / 2, region: String::from("SG") })
+            .collect();
+        let table = RangeTable::from_ranges(ip_list);
+
+        let mut rng = rand::rng();
+        let lookups: Vec<u32> = (0..200_000).map(|_| rng.random::<u32>()).collect();
+        let start = Instant::now();
+        for ip in &lookups {
+            black_box(table.get_region(*ip));
+        }
+        let per_lookup = start.elapsed() / lookups.len() as u32;
+        assert!(per_lookup.as_nanos() < 1000, "lookup took {:?}", per_lookup);
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use log::{error, info};
+use crate::util::{data_path, write_atomic};
+
+lazy_static! {
+    pub static ref LABELS: Mutex<Labels> = Mutex::new(Labels::load(&data_path("history").join("labels.json").to_string_lossy()));
+}
+
+const MAX_LABEL_LENGTH: usize = 100;
+
+pub fn is_valid_label(label: &str) -> bool {
+    !label.trim().is_empty() && label.chars().count() <= MAX_LABEL_LENGTH && !label.chars().any(char::is_control)
+}
+
+/// Labels of history snapshots, by file name and snapshot time. Labeled snapshots are never pruned.
+/// Persisted to `filename`. When the file can not be read, the labels are unknown: nothing is
+/// saved over the file and the retention does not prune.
+#[derive(Clone)]
+pub struct Labels {
+    filename: Option<String>,
+    labels: HashMap<String, BTreeMap<i64, String>>,
+    error: Option<String>,
+}
+
+impl Labels {
+    pub fn new() -> Self {
+        Labels {
+            filename: None,
+            labels: HashMap::new(),
+            error: None,
+        }
+    }
+
+    pub fn load(filename: &str) -> Self {
+        let mut labels = Labels::new();
+        labels.filename = Some(filename.to_string());
+        match fs::read_to_string(filename) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(value) => {
+                    labels.labels = value;
+                    info!("Loaded snapshot labels from {}", filename);
+                }
+                Err(e) => labels.error = Some(format!("Failed to parse {}: {}", filename, e)),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => labels.error = Some(format!("Failed to read {}: {}", filename, e)),
+        }
+        if let Some(e) = &labels.error {
+            error!("{}", e);
+        }
+        labels
+    }
+
+    /// Why the labels file could not be read, the labels are unknown then.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    fn save(&self) -> Result<(), std::io::Error> {
+        let filename = match &self.filename {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+        write_atomic(Path::new(filename), serde_json::to_string_pretty(&self.labels).unwrap().as_bytes())
+    }
+
+    pub fn get(&self, filename: &str, timestamp: i64) -> Option<&String> {
+        self.labels.get(filename).and_then(|labels| labels.get(&timestamp))
+    }
+
+    /// Sets or, with `None`, removes the label of a snapshot.
+    pub fn set(&mut self, filename: &str, timestamp: i64, label: Option<String>) -> Result<(), std::io::Error> {
+        // saving would replace the unreadable file and lose every label in it
+        if let Some(e) = &self.error {
+            return Err(std::io::Error::other(e.clone()));
+        }
+        match label {
+            Some(label) => {
+                self.labels.entry(filename.to_string()).or_default().insert(timestamp, label);
+            }
+            None => {
+                if let Some(labels) = self.labels.get_mut(filename) {
+                    labels.remove(&timestamp);
+                    if labels.is_empty() {
+                        self.labels.remove(filename);
+                    }
+                }
+            }
+        }
+        self.save()
+    }
+}
+
+// test module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_label() {
+        assert!(is_valid_label("before cleanup"));
+        assert!(is_valid_label("Q3 research"));
+        assert!(!is_valid_label(" "));
+        assert!(!is_valid_label("line\nbreak"));
+        assert!(!is_valid_label(&"x".repeat(101)));
+    }
+
+    #[test]
+    fn test_set_and_persist() {
+        let filename = std::env::temp_dir().join(format!("bot-labels-{}.json", std::process::id()));
+        let filename = filename.to_string_lossy();
+        let mut labels = Labels::load(&filename);
+        labels.set("alice.json", 100, Some(String::from("before cleanup"))).unwrap();
+        labels.set("alice.json", 200, Some(String::from("Q3 research"))).unwrap();
+        labels.set("alice.json", 200, None).unwrap();
+
+        let labels = Labels::load(&filename);
+        assert_eq!(labels.get("alice.json", 100), Some(&String::from("before cleanup")));
+        assert_eq!(labels.get("alice.json", 200), None);
+        assert_eq!(labels.get("bob.json", 100), None);
+        fs::remove_file(filename.as_ref()).unwrap();
+    }
+
+    #[test]
+    fn test_unreadable_file_is_kept() {
+        let filename = std::env::temp_dir().join(format!("bot-labels-corrupt-{}.json", std::process::id()));
+        fs::write(&filename, r#"{"alice.json": {"100": "bef"#).unwrap();
+        let filename = filename.to_string_lossy();
+        let mut labels = Labels::load(&filename);
+        assert!(labels.error().unwrap().starts_with("Failed to parse"));
+        assert!(labels.set("alice.json", 200, Some(String::from("Q3 research"))).is_err());
+        assert_eq!(fs::read_to_string(filename.as_ref()).unwrap(), r#"{"alice.json": {"100": "bef"#);
+        fs::remove_file(filename.as_ref()).unwrap();
+    }
+}
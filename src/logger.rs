@@ -33,7 +33,7 @@ impl Filter for PassThroughFilter {
     }
 }
 
-pub fn setup_logger() -> Result<Handle, Box<dyn std::error::Error>> {
+pub fn setup_logger(log_dir: &str) -> Result<Handle, Box<dyn std::error::Error>> {
     // 获取当前日期
 
     let mut config_builder = log4rs::config::runtime::ConfigBuilder::default();
@@ -42,13 +42,13 @@ pub fn setup_logger() -> Result<Handle, Box<dyn std::error::Error>> {
         // 配置日志滚动策略
         let size_trigger = SizeTrigger::new(10 * 1024); // 10MB
         let size_roller = FixedWindowRoller::builder()
-            .build(&format!("logs/{}/debug.app.rotate.{{}}.log", Local::now().format("%Y-%m-%d").to_string()), 30)?;
+            .build(&format!("{}/{}/debug.app.rotate.{{}}.log", log_dir, Local::now().format("%Y-%m-%d")), 30)?;
         let size_trigger_policy = CompoundPolicy::new(Box::new(size_trigger), Box::new(size_roller));
         // 配置日志附加器
         let size_rolled_appender = RollingFileAppender::builder()
             .append(true)
             .encoder(Box::new(PatternEncoder::new("{d}, {l}, {m}{n}")))
-            .build(&format!("logs/{}/debug.app.log", Local::now().format("%Y-%m-%d").to_string()), Box::new(size_trigger_policy))?;
+            .build(format!("{}/{}/debug.app.log", log_dir, Local::now().format("%Y-%m-%d")), Box::new(size_trigger_policy))?;
         config_builder = config_builder.appender(
             Appender::builder()
                 .filter(
@@ -65,13 +65,13 @@ pub fn setup_logger() -> Result<Handle, Box<dyn std::error::Error>> {
         // 配置日志滚动策略
         let size_trigger = SizeTrigger::new(10 * 1024); // 10KB
         let size_roller = FixedWindowRoller::builder()
-            .build(&format!("logs/{}/info.app.rotate.{{}}.log", Local::now().format("%Y-%m-%d").to_string()), 30)?;
+            .build(&format!("{}/{}/info.app.rotate.{{}}.log", log_dir, Local::now().format("%Y-%m-%d")), 30)?;
         let size_trigger_policy = CompoundPolicy::new(Box::new(size_trigger), Box::new(size_roller));
         // 配置日志附加器
         let size_rolled_appender = RollingFileAppender::builder()
             .append(true)
             .encoder(Box::new(PatternEncoder::new("{d}, {l}, {m}{n}")))
-            .build(&format!("logs/{}/info.app.log", Local::now().format("%Y-%m-%d").to_string()), Box::new(size_trigger_policy))?;
+            .build(format!("{}/{}/info.app.log", log_dir, Local::now().format("%Y-%m-%d")), Box::new(size_trigger_policy))?;
         config_builder = config_builder.appender(
             Appender::builder()
                 .filter(
@@ -89,13 +89,13 @@ pub fn setup_logger() -> Result<Handle, Box<dyn std::error::Error>> {
         // 配置日志滚动策略
         let size_trigger = SizeTrigger::new(10 * 1024); // 10KB
         let size_roller = FixedWindowRoller::builder()
-            .build(&format!("logs/{}/error.app.rotate.{{}}.log", Local::now().format("%Y-%m-%d").to_string()), 30)?;
+            .build(&format!("{}/{}/error.app.rotate.{{}}.log", log_dir, Local::now().format("%Y-%m-%d")), 30)?;
         let size_trigger_policy = CompoundPolicy::new(Box::new(size_trigger), Box::new(size_roller));
         // 配置日志附加器
         let size_rolled_appender = RollingFileAppender::builder()
             .append(true)
             .encoder(Box::new(PatternEncoder::new("{d}, {l}, {m}{n}")))
-            .build(&format!("logs/{}/error.app.log", Local::now().format("%Y-%m-%d").to_string()), Box::new(size_trigger_policy))?;
+            .build(format!("{}/{}/error.app.log", log_dir, Local::now().format("%Y-%m-%d")), Box::new(size_trigger_policy))?;
         config_builder = config_builder.appender(
             Appender::builder()
                 .filter(
@@ -2,45 +2,73 @@
 extern crate lazy_static;
 
 use std::collections::HashMap;
-use std::env::args;
+use std::sync::Arc;
 use std::net::SocketAddr;
-use std::sync::Mutex;
+
+use arc_swap::ArcSwap;
 
 use axum::{
     http::HeaderMap,
     http::StatusCode,
-    Json, response::Response,
+    Json, response::{Redirect, Response},
     Router,
     routing::{get, post},
 };
-use axum::body::HttpBody;
-use axum::extract::{ConnectInfo, Path, Query, Request};
+use axum::extract::{ConnectInfo, Extension, Path, Query, Request};
 use axum::middleware::Next;
-use serde::{Deserialize};
+use axum::response::IntoResponse;
 use tower_http::cors::{Any, CorsLayer};
-use log::{debug, error, info, trace, warn};
+use log::{debug, error, info, warn};
 use uuid::Uuid;
-use crate::config::Config;
-use crate::ip::Ips;
+use crate::ban::{is_failure_status, BANS};
+use crate::client_ip::{resolve_client_ip, ClientInfo};
+use clap::Parser;
+use crate::cli::{Cli, Command};
+use crate::config::{Config, PolicyAction, CONFIG_INSTANCE};
+use crate::ip::{IpLists, Ips, Reloadable};
+use crate::policy::{region_code_violations, route_group, PolicyInput};
 use crate::models::tabs::{TabGroup, Tabs};
-use crate::models::update_response::update_response;
+use crate::models::login_response::LoginResponse;
+use crate::models::region_lock::RegionLock;
+use crate::models::history::{SnapshotInfo, SnapshotRequest};
+use crate::history::SnapshotMeta;
+use crate::labels::{is_valid_label, LABELS};
+use crate::models::update_response::UpdateResponse;
 use crate::models::user::User;
-use crate::util::{generate_random_string, get_tabs_from_file, read_lines_from_file, remove_user_token, save_tabs_to_file, save_token_to_file, try_get_username_token};
+use crate::util::{generate_random_string, load_tabs, create_snapshot, get_user_region_lock, list_history, read_history, read_lines_from_file, remove_user_token, save_tabs_to_file, save_token_to_file, set_user_region_lock, try_get_username_token};
 
 mod util;
+mod cli;
 mod logger;
 mod config;
 mod ip;
+mod client_ip;
+mod policy;
+mod oidc;
+mod admin;
+mod ban;
+mod retention;
+mod labels;
+mod audit;
+mod history;
+mod git_history;
+mod fsck;
+mod backup;
 
 mod models {
     pub mod user; // 引入 greet_world 模块
     pub mod tabs; // 引入 greet_world 模块
     pub mod update_response;
+    pub mod login_response;
+    pub mod region_lock;
+    pub mod history;
 }
 
 lazy_static! {
-    pub static ref IPS_INSTANCE: Mutex<Ips> = Mutex::new(Ips::new());
-    pub static ref CONFIG_INSTANCE: Mutex<Config> = Mutex::new(Config::new());
+    // read on every request, swapped as a whole instead of locked
+    pub static ref IPS_INSTANCE: ArcSwap<Ips> = ArcSwap::from_pointee(Ips::load(&CONFIG_INSTANCE.load().settings.geoip_files));
+    pub static ref ASN_INSTANCE: ArcSwap<Ips> = ArcSwap::from_pointee(Ips::load(&CONFIG_INSTANCE.load().settings.asn_files));
+    pub static ref IP_LISTS_INSTANCE: ArcSwap<IpLists> = ArcSwap::from_pointee(IpLists::load(&CONFIG_INSTANCE.load().settings.ip_lists));
 }
 
 #[tokio::main]
@@ -52,25 +80,41 @@ async fn main() {
     //         return;
     //     },
     // };
-    logger::setup_logger().unwrap();
-    info!("Starting server... at {}", chrono::Utc::now());
-    let params: Vec<String> = args().collect();
-    if params.len() < 2 {
-        println!("Usage: {} <port>", params[0]);
-        println!("data directory should be created in the current directory");
-
-        error!("Error: missing port number");
-        return;        
+    let cli = Cli::parse();
+    config::set_config_source(cli.config_source());
+    // an invalid config stops the server instead of running on defaults
+    let config = match Config::load(config::config_source()) {
+        Ok(value) => value,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let settings = config.settings.clone();
+    CONFIG_INSTANCE.store(Arc::new(config));
+
+    match cli.command {
+        None | Some(Command::Serve { .. }) => {}
+        Some(command) => {
+            if let Err(e) = cli::run(command) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
     }
 
+    logger::setup_logger(&settings.log_dir).unwrap();
+    info!("Starting server... at {}", chrono::Utc::now());
+    info!("Loaded config: {}", settings);
+
     // check data directory
-    let current_dir = std::env::current_dir().unwrap();
-    println!("Current directory: {:?}", current_dir);
-    let data_dir = current_dir.join("data");
+    let data_dir = std::path::Path::new(&settings.data_dir);
+    println!("Data directory: {:?}", data_dir);
     if !data_dir.exists() {
         println!("Create data directory: {:?} and users.txt", data_dir);
-        error!("Error: missing data directory");
-        return;
+        error!("Error: missing data directory {:?}", data_dir);
+        std::process::exit(1);
     }
     // check history directory
     let history_dir = data_dir.join("history");
@@ -79,33 +123,62 @@ async fn main() {
         println!("Create history directory: {:?}", history_dir);
         warn!("Warning: missing history directory and created");
     }
-    
-    let port = params[1].parse::<u16>().unwrap();
-    println!("Listening on port {}", port);
-    info!("Listening on port {}", port);
-    
+    // the labels pin snapshots, running without them would prune or overwrite them
+    if let Some(e) = LABELS.lock().unwrap().error() {
+        eprintln!("Error: {}, fix or move the file aside before starting", e);
+        error!("Error: {}", e);
+        std::process::exit(1);
+    }
+
+    println!("Listening on {}", settings.bind);
+    info!("Listening on {}", settings.bind);
+
+    spawn_reload_tasks();
+    if settings.retention_interval > 0 {
+        tokio::spawn(retention::worker(settings.retention_interval));
+    }
+
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
         .allow_headers(Any)
+        // the extension reads these after a recovery
+        .expose_headers([axum::http::header::WARNING, axum::http::HeaderName::from_static("x-tabs-recovered-from")])
     ;
     let middle_ware = axum::middleware::from_fn (ip_filter_middleware);
+    let ban_middle_ware = axum::middleware::from_fn (ban_middleware);
     // build our application with a route
     let app = Router::new()
         // `GET /` goes to `root`
         .route("/", get(root))
         .route("/api/", get(root))
         .route("/api/verify", post(verify_user).options(options_handler))
+        .route("/api/oidc/login", get(oidc_login))
+        .route("/api/oidc/callback", get(oidc_callback))
         .route("/api/user/:username/logout", post(logout_user).options(options_handler))
         .route("/api/user/:username", get(get_user_info))
         .route("/api/user/:username/tabs", post(update_tabs).options(options_handler))
         .route("/api/user/:username/tabs", get(get_tabs))
+        .route("/api/user/:username/region-lock", post(update_region_lock).options(options_handler))
+        .route("/api/user/:username/history", get(get_history).post(create_history_snapshot).options(options_handler))
+        .route("/api/user/:username/history/:timestamp", get(get_history_snapshot))
+        .route("/api/user/:username/history/:timestamp/label", post(label_history_snapshot).options(options_handler))
+        .route("/api/admin/geoip/reload", post(admin::reload_geoip))
+        .route("/api/admin/config/reload", post(admin::reload_config))
+        .route("/api/admin/retention/run", post(admin::run_retention))
+        .route("/api/admin/retention/metrics", get(admin::retention_metrics))
+        .route("/api/admin/audit", get(admin::list_audit_events))
+        .route("/api/admin/fsck", post(admin::run_fsck))
+        .route("/api/admin/backup", post(admin::create_backup))
+        .route("/api/admin/bans", get(admin::list_bans))
+        .route("/api/admin/bans/:ip/unban", post(admin::unban))
         .layer(middle_ware)
+        .layer(ban_middle_ware)
         .layer(cors)
         ;
 
     // run our app with hyper, listening globally on port 3000
-    match tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port)).await {
+    match tokio::net::TcpListener::bind(settings.bind).await {
         Ok(listener) => {
             match axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await {
                 Ok(_) => {
@@ -125,18 +198,118 @@ async fn main() {
     }
 }
 
-async fn ip_filter_middleware(
+/// Reloads the GeoIP, ASN and ip list tables, a table that fails validation keeps its current data.
+pub async fn reload_geoip_databases() -> Result<String, String> {
+    let (geoip_files, asn_files, ip_lists) = {
+        let settings = &CONFIG_INSTANCE.load().settings;
+        (settings.geoip_files.clone(), settings.asn_files.clone(), settings.ip_lists.clone())
+    };
+    let mut results = vec![
+        ip::reload(&IPS_INSTANCE, "GeoIP", move || Ips::load(&geoip_files)).await
+            .map(|range_count| format!("{} GeoIP ranges", range_count)),
+        ip::reload(&IP_LISTS_INSTANCE, "ip lists", move || IpLists::load(&ip_lists)).await
+            .map(|range_count| format!("{} ip list ranges", range_count)),
+    ];
+    if !asn_files.is_empty() {
+        results.push(ip::reload(&ASN_INSTANCE, "ASN", move || Ips::load(&asn_files)).await
+            .map(|range_count| format!("{} ASN ranges", range_count)));
+    }
+    let errors: Vec<String> = results.iter().filter_map(|result| result.clone().err()).collect();
+    if !errors.is_empty() {
+        return Err(errors.join(", "));
+    }
+    Ok(format!("Loaded {}", results.into_iter().filter_map(Result::ok).collect::<Vec<String>>().join(", ")))
+}
+
+fn spawn_reload_tasks() {
+    let (watched_files, geoip_watch_interval, config_watch_interval) = {
+        let settings = &CONFIG_INSTANCE.load().settings;
+        let watched_files = settings.geoip_files.iter()
+            .chain(settings.asn_files.iter())
+            .chain(settings.ip_lists.values())
+            .cloned()
+            .collect::<Vec<String>>();
+        (watched_files, settings.geoip_watch_interval, settings.config_watch_interval)
+    };
+    if IPS_INSTANCE.load().validate().is_err() {
+        warn!("Warning: GeoIP table is empty or invalid");
+    }
+    if geoip_watch_interval > 0 {
+        tokio::spawn(ip::watch(watched_files, geoip_watch_interval, || async {
+            let _ = reload_geoip_databases().await;
+        }));
+    }
+    let config_file = config::config_source().config_file();
+    if let (true, Some(config_file)) = (config_watch_interval > 0, config_file) {
+        tokio::spawn(ip::watch(vec![config_file.to_string_lossy().into_owned()], config_watch_interval, || async {
+            let _ = config::reload();
+        }));
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(value) => value,
+            Err(e) => {
+                error!("Failed to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reloading");
+            let _ = config::reload();
+            let _ = reload_geoip_databases().await;
+        }
+    });
+}
+
+/// Rejects banned ips, and counts error responses of everything behind it, including region blocks.
+async fn ban_middleware(
     ConnectInfo(socket_addr): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
     request: Request,
     next: Next,
+) -> Response {
+    let (ban_settings, ip) = {
+        let settings = &CONFIG_INSTANCE.load().settings;
+        (settings.ban.clone(), resolve_client_ip(socket_addr.ip(), &headers, &settings.trusted_proxies, settings.forwarded_header))
+    };
+    let ip = match (ban_settings.enabled, ip) {
+        (true, Ok(value)) => value,
+        _ => return next.run(request).await,
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    if let Some(ban) = BANS.lock().unwrap().get_ban(ip, now) {
+        info!("Banned ip  {} - {} {}", ip, request.method(), request.uri().path());
+        return Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(axum::body::Body::from(format!("Banned until {}", ban.until)))
+            .unwrap();
+    }
+
+    let reason = format!("{} {}", request.method(), request.uri().path());
+    let response = next.run(request).await;
+    if is_failure_status(response.status()) {
+        let reason = format!("{} {}", response.status().as_u16(), reason);
+        BANS.lock().unwrap().record_failure(ip, &reason, now, &ban_settings);
+    }
+    response
+}
+
+async fn ip_filter_middleware(
+    ConnectInfo(socket_addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    mut request: Request,
+    next: Next,
 ) -> Response {
     let request_id = Uuid::new_v4();
     {
 
         info!("{}, {}, {}, {}, headers: {:?}", socket_addr, request.method(), request.uri().path(), &request_id.to_string(), headers);
-        let settings = &(CONFIG_INSTANCE.lock().unwrap().settings);
-        if settings.enable_region_block == true {
+        let settings = &CONFIG_INSTANCE.load().settings;
+        if settings.enable_region_block {
             let all_headers = headers.clone();
             for (name, value) in all_headers.iter() {
                 if name.as_str().contains("agent") {
@@ -144,24 +317,33 @@ async fn ip_filter_middleware(
                 }
                 debug!("{}: {}", name, value.to_str().unwrap_or("header no value"));
             }
-            
-            let ip_str = headers
-                .get("x-forwarded-for")
-                .and_then(|value| value.to_str().ok())
-                .unwrap_or("127.0.0.1");
-
-            debug!("ip: {}", ip_str);
-
-            let ip_parts: Vec<&str> = ip_str.split(".").collect();
-            let ip_u32 = ip_parts[0].parse::<u32>().unwrap() * 256 * 256 * 256 + ip_parts[1].parse::<u32>().unwrap() * 256 * 256 + ip_parts[2].parse::<u32>().unwrap() * 256 + ip_parts[3].parse::<u32>().unwrap();
+        }
 
-            debug!("ip_u32: {}", ip_u32);
+        // the region is resolved even without region block, user region locks need it
+        let (ip, region_code, asn) = match resolve_client_ip(socket_addr.ip(), &headers, &settings.trusted_proxies, settings.forwarded_header) {
+            Ok(value) => (Some(value), IPS_INSTANCE.load().get_region(value).to_string(), ASN_INSTANCE.load().get_asn(value)),
+            Err(invalid_hop) => {
+                if settings.enable_region_block && settings.invalid_ip_action == PolicyAction::Deny {
+                    info!("Forbidden invalid ip  {} - {} {}", invalid_hop, request.method(), request.uri().path());
+                    let forbidden_response = Response::builder()
+                        .status(StatusCode::FORBIDDEN)
+                        .body(axum::body::Body::from(format!("Forbidden ip: {}", invalid_hop)))
+                        .unwrap();
+                    return forbidden_response;
+                }
+                info!("Allowed invalid ip  {} - {} {}", invalid_hop, request.method(), request.uri().path());
+                (None, String::from("unknown"), None)
+            }
+        };
 
-            let region_code = IPS_INSTANCE.lock().unwrap().get_region(ip_u32);
-            debug!("{} - {} {} {}", ip_str, request.method(), request.uri().path(), region_code);
+        if let (true, Some(ip)) = (settings.enable_region_block, ip) {
+            let route_group = route_group(request.method(), request.uri().path());
+            let lists = IP_LISTS_INSTANCE.load().lists_containing(ip);
+            debug!("{} - {} {} {} {} {:?} {:?}", ip, request.method(), request.uri().path(), route_group, region_code, asn, lists);
 
-            if !settings.contains_region(&region_code) {
-                info!("Forbidden ip  {} - {} {} {}", ip_str, request.method(), request.uri().path(), region_code);
+            let decision = settings.route_policy(route_group).evaluate(&PolicyInput { ip, region: &region_code, asn, lists: &lists });
+            if decision.action == PolicyAction::Deny {
+                info!("Forbidden ip  {} - {} {} {}, {}", ip, request.method(), request.uri().path(), region_code, decision);
                 let forbidden_message = format!("Forbidden region: {}", region_code);
                 let forbidden_response = Response::builder()
                     .status(StatusCode::FORBIDDEN)
@@ -169,8 +351,10 @@ async fn ip_filter_middleware(
                     .unwrap();
                 return forbidden_response;
             }
-            info!("Allowed ip  {} - {} {} {}", ip_str, request.method(), request.uri().path(), region_code);
+            info!("Allowed ip  {} - {} {} {}, {}", ip, request.method(), request.uri().path(), region_code, decision);
         }
+
+        request.extensions_mut().insert(ClientInfo { ip, region: region_code });
     }
 
     let response = next.run(request).await;
@@ -185,6 +369,7 @@ async fn root() -> (StatusCode, Json<String>) {
 }
 
 async fn verify_user(
+    Extension(client): Extension<ClientInfo>,
     Json(payload): Json<User>,
 ) -> (StatusCode, Json<String>) {
     let filename = "users.txt";
@@ -199,7 +384,7 @@ async fn verify_user(
         }
     }
 
-    if users.len() == 0 {
+    if users.is_empty() {
         return (StatusCode::INTERNAL_SERVER_ERROR, Json("No users".to_string()));
     }
 
@@ -209,15 +394,93 @@ async fn verify_user(
 
     for user in users {
         if user.username == payload.username && user.password == payload.password {
-            let token = generate_random_string(32);
-            save_token_to_file(format!("{}.txt", user.username), token.clone()).unwrap();
-            return (StatusCode::OK, Json(token));
+            if !region_lock_allows(&user.region_lock, &client) {
+                info!("Region lock of {} rejects login from {:?} {}", user.username, client.ip, client.region);
+                return (StatusCode::FORBIDDEN, Json(format!("Forbidden region: {}", client.region)));
+            }
+            return match issue_token(&user.username) {
+                Ok(token) => (StatusCode::OK, Json(token)),
+                Err(e) => {
+                    error!("Error saving token of {}: {}", user.username, e);
+                    (StatusCode::INTERNAL_SERVER_ERROR, Json(format!("Error saving token: {}", e)))
+                }
+            };
         }
     }
 
     (StatusCode::UNAUTHORIZED, Json("Incorrect username or password".to_string()))
 }
 
+fn region_lock_allows(region_lock: &[String], client: &ClientInfo) -> bool {
+    region_lock.is_empty() || region_lock.contains(&client.region)
+}
+
+/// Checks the personal region lock of an authenticated user.
+fn user_region_lock_allows(username: &str, client: &ClientInfo) -> bool {
+    let allowed = region_lock_allows(&get_user_region_lock(username), client);
+    if !allowed {
+        info!("Region lock of {} rejects request from {:?} {}", username, client.ip, client.region);
+    }
+    allowed
+}
+
+fn issue_token(username: &str) -> Result<String, std::io::Error> {
+    let token = generate_random_string(32);
+    save_token_to_file(format!("{}.txt", username), token.clone())?;
+    Ok(token)
+}
+
+async fn oidc_login() -> Response {
+    let oidc_settings = CONFIG_INSTANCE.load().settings.oidc.clone();
+    let oidc_settings = match oidc_settings {
+        Some(value) => value,
+        None => return (StatusCode::NOT_FOUND, Json("OIDC login is not configured".to_string())).into_response(),
+    };
+    match oidc::begin_login(&oidc_settings).await {
+        Ok(Some(url)) => Redirect::to(&url).into_response(),
+        Ok(None) => (StatusCode::SERVICE_UNAVAILABLE, Json("Too many pending logins, try again later".to_string())).into_response(),
+        Err(e) => {
+            error!("OIDC login failed: {}", e);
+            (StatusCode::BAD_GATEWAY, Json(e)).into_response()
+        }
+    }
+}
+
+async fn oidc_callback(Extension(client): Extension<ClientInfo>, Query(params): Query<HashMap<String, String>>) -> Response {
+    let oidc_settings = CONFIG_INSTANCE.load().settings.oidc.clone();
+    let oidc_settings = match oidc_settings {
+        Some(value) => value,
+        None => return (StatusCode::NOT_FOUND, Json("OIDC login is not configured".to_string())).into_response(),
+    };
+    if let Some(e) = params.get("error") {
+        warn!("OIDC provider returned error: {}", e);
+        return (StatusCode::UNAUTHORIZED, Json(format!("Login failed: {}", e))).into_response();
+    }
+    let (code, state) = match (params.get("code"), params.get("state")) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return (StatusCode::BAD_REQUEST, Json("Missing code or state".to_string())).into_response(),
+    };
+    let username = match oidc::complete_login(&oidc_settings, code, state).await {
+        Ok(value) => value,
+        Err(e) => {
+            warn!("OIDC callback rejected: {}", e);
+            return (StatusCode::UNAUTHORIZED, Json(e)).into_response();
+        }
+    };
+    if !user_region_lock_allows(&username, &client) {
+        return (StatusCode::FORBIDDEN, Json(format!("Forbidden region: {}", client.region))).into_response();
+    }
+    // before the token, so fsck never sees a token of an unknown user
+    if let Err(e) = oidc::record_oidc_user(&username) {
+        error!("Error recording OIDC user {}: {}", username, e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, Json(format!("Error saving user: {}", e))).into_response();
+    }
+    match issue_token(&username) {
+        Ok(token) => (StatusCode::OK, Json(LoginResponse { username, token })).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(format!("Error saving token: {}", e))).into_response(),
+    }
+}
+
 async fn logout_user(
     Path(username): Path<String>, Json(payload): Json<String>,
 ) -> (StatusCode, Json<String>) {
@@ -231,29 +494,54 @@ async fn logout_user(
 }
 
 async fn update_tabs(
-    Path(username): Path<String>, Json(payload): Json<Tabs>
-) -> (StatusCode, Json<update_response>) {
+    Extension(client): Extension<ClientInfo>, Path(username): Path<String>, headers: HeaderMap, Json(payload): Json<Tabs>
+) -> (StatusCode, Json<UpdateResponse>) {
     let tabs = payload.tabs;
     let token = payload.token;
     let check_token = try_get_username_token(&username, token.to_string());
     if !check_token {
-        return (StatusCode::UNAUTHORIZED, Json(update_response {
+        return (StatusCode::UNAUTHORIZED, Json(UpdateResponse {
             message: "Not found token".to_string(),
             updated_at: chrono::Utc::now()
         }));
     }
+    if !user_region_lock_allows(&username, &client) {
+        return (StatusCode::FORBIDDEN, Json(UpdateResponse {
+            message: format!("Forbidden region: {}", client.region),
+            updated_at: chrono::Utc::now()
+        }));
+    }
+
+    if payload.label.as_deref().is_some_and(|label| !is_valid_label(label)) {
+        return (StatusCode::BAD_REQUEST, Json(UpdateResponse {
+            message: "Invalid label".to_string(),
+            updated_at: chrono::Utc::now()
+        }));
+    }
 
     let json_str = serde_json::to_string(&tabs).unwrap();
     let filename = format!("{}.json", username);
-    return match save_tabs_to_file(&filename, json_str) {
-        Ok(()) => {
-            (StatusCode::OK, Json(update_response {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).map(String::from);
+    let meta = SnapshotMeta {
+        ip: client.ip.map(|ip| ip.to_string()),
+        region: Some(client.region.clone()),
+        user_agent: header("user-agent"),
+        client_version: header("x-client-version"),
+        ..SnapshotMeta::new(&username, &json_str)
+    };
+    let (file, label) = (filename.clone(), payload.label);
+    match run_blocking(move || save_tabs_to_file(&file, json_str, meta, label)).await {
+        Ok(changed) => {
+            if !changed {
+                debug!("Tabs of {} did not change", username);
+            }
+            (StatusCode::OK, Json(UpdateResponse {
                 message: "OK".to_string(),
                 updated_at: chrono::Utc::now()
             }))
         }
         Err(e) => {
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(update_response {
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(UpdateResponse {
                 message: format!("Error saving file {}: {}", filename, e),
                 updated_at: chrono::Utc::now()
             }))
@@ -261,16 +549,44 @@ async fn update_tabs(
     }
 }
 
-async fn get_user_info(Path(username): Path<String>, Query(params): Query<HashMap<String, String>>) -> (StatusCode, Json<String>) {
+async fn get_user_info(Extension(client): Extension<ClientInfo>, Path(username): Path<String>, Query(params): Query<HashMap<String, String>>) -> (StatusCode, Json<String>) {
     let token = params.get("token").unwrap();
     let result = try_get_username_token(&username, token.to_string());
     if result {
+        if !user_region_lock_allows(&username, &client) {
+            return (StatusCode::FORBIDDEN, Json(format!("Forbidden region: {}", client.region)));
+        }
         return (StatusCode::OK, Json("OK".to_string()));
     }
 
     (StatusCode::UNAUTHORIZED, Json("Not found token".to_string()))
 }
 
+async fn update_region_lock(
+    Extension(client): Extension<ClientInfo>, Path(username): Path<String>, Json(payload): Json<RegionLock>
+) -> (StatusCode, Json<String>) {
+    // the regions are written into users.txt
+    let violations = region_code_violations("regions", &payload.regions);
+    if !violations.is_empty() {
+        return (StatusCode::BAD_REQUEST, Json(violations.join("; ")));
+    }
+    if !try_get_username_token(&username, payload.token.to_string()) {
+        return (StatusCode::UNAUTHORIZED, Json("Not found token".to_string()));
+    }
+    if !user_region_lock_allows(&username, &client) {
+        return (StatusCode::FORBIDDEN, Json(format!("Forbidden region: {}", client.region)));
+    }
+    // do not let users lock themselves out from where they are
+    if !region_lock_allows(&payload.regions, &client) {
+        return (StatusCode::BAD_REQUEST, Json(format!("Current region {} must be in the region lock", client.region)));
+    }
+    match set_user_region_lock(&username, payload.regions) {
+        Ok(true) => (StatusCode::OK, Json("OK".to_string())),
+        Ok(false) => (StatusCode::NOT_FOUND, Json("No such user".to_string())),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(format!("Error saving file users.txt: {}", e))),
+    }
+}
+
 async fn options_handler() -> Response {
     Response::builder()
         .header("Access-Control-Allow-Origin", "*")
@@ -281,32 +597,167 @@ async fn options_handler() -> Response {
 }
 
 
-async fn get_tabs(Path(username): Path<String>, Query(params): Query<HashMap<String, String>>) -> (StatusCode, Json<Tabs>) {
+/// Runs file work off the async workers, it waits for the per-user file lock and fsyncs.
+async fn run_blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T, std::io::Error> + Send + 'static) -> Result<T, std::io::Error> {
+    tokio::task::spawn_blocking(f).await.unwrap_or_else(|e| Err(std::io::Error::other(e)))
+}
+
+/// Tabs of the user. When the current file was corrupt and a snapshot was served instead,
+/// the `Warning` and `X-Tabs-Recovered-From` headers say so.
+async fn get_tabs(Extension(client): Extension<ClientInfo>, Path(username): Path<String>, Query(params): Query<HashMap<String, String>>) -> Response {
     let token = params.get("token").unwrap();
     let result = try_get_username_token(&username, token.to_string());
     if result {
+        if !user_region_lock_allows(&username, &client) {
+            return (StatusCode::FORBIDDEN, Json(Tabs {
+                tabs: Vec::new(),
+                token: "".to_string(),
+                label: None,
+            })).into_response();
+        }
         let filename = format!("{}.json", username);
-        return match get_tabs_from_file(filename) {
-            Ok(tabs) => {
-                // println!("tabs: {}", tabs);
-                let tabs: Vec<TabGroup> = serde_json::from_str(&tabs).unwrap();                
-                (StatusCode::OK, Json(Tabs {
-                    tabs: tabs,
-                    token: "".to_string()
-                }))
+        return match run_blocking(move || load_tabs(&filename)).await {
+            Ok(loaded) => {
+                let mut headers = HeaderMap::new();
+                if let Some(timestamp) = loaded.recovered_from {
+                    let warning = format!("199 - \"Tabs were corrupt and are recovered from snapshot {}\"", timestamp);
+                    headers.insert(axum::http::header::WARNING, warning.parse().unwrap());
+                    headers.insert("x-tabs-recovered-from", timestamp.into());
+                }
+                (StatusCode::OK, headers, Json(Tabs {
+                    tabs: loaded.tabs,
+                    token: "".to_string(),
+                    label: None,
+                })).into_response()
             }
             Err(e) => {
+                error!("Error reading tabs of {}: {}", username, e);
                 (StatusCode::INTERNAL_SERVER_ERROR , Json(Tabs {
                     tabs: Vec::new(),
-                    token: "".to_string()
-                }))
+                    token: "".to_string(),
+                    label: None,
+                })).into_response()
             }
         }
     }
 
     (StatusCode::UNAUTHORIZED, Json(Tabs {
         tabs: Vec::new(),
-        token: "".to_string()
-    }))
+        token: "".to_string(),
+        label: None,
+    })).into_response()
 }
 
+/// Snapshots of the user, `?labeled=true` lists only labeled ones.
+async fn get_history(
+    Extension(client): Extension<ClientInfo>, Path(username): Path<String>, Query(params): Query<HashMap<String, String>>
+) -> (StatusCode, Json<Vec<SnapshotInfo>>) {
+    let token = params.get("token").cloned().unwrap_or_default();
+    if !try_get_username_token(&username, token) {
+        return (StatusCode::UNAUTHORIZED, Json(Vec::new()));
+    }
+    if !user_region_lock_allows(&username, &client) {
+        return (StatusCode::FORBIDDEN, Json(Vec::new()));
+    }
+    let labeled_only = params.get("labeled").is_some_and(|labeled| labeled == "true");
+    let filename = format!("{}.json", username);
+    match run_blocking(move || list_history(Some(&filename))).await {
+        Ok(snapshots) => (StatusCode::OK, Json(snapshots.into_iter()
+            .filter(|snapshot| !labeled_only || snapshot.label.is_some())
+            .map(|snapshot| SnapshotInfo { timestamp: snapshot.timestamp, size: snapshot.size, label: snapshot.label, meta: snapshot.meta })
+            .collect())),
+        Err(e) => {
+            error!("Error reading history of {}: {}", username, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(Vec::new()))
+        }
+    }
+}
+
+/// Tabs of a snapshot, in the shape of `GET /api/user/{username}/tabs`.
+async fn get_history_snapshot(
+    Extension(client): Extension<ClientInfo>, Path((username, timestamp)): Path<(String, i64)>, Query(params): Query<HashMap<String, String>>
+) -> (StatusCode, Json<Tabs>) {
+    let empty = || Json(Tabs { tabs: Vec::new(), token: "".to_string(), label: None });
+    let token = params.get("token").cloned().unwrap_or_default();
+    if !try_get_username_token(&username, token) {
+        return (StatusCode::UNAUTHORIZED, empty());
+    }
+    if !user_region_lock_allows(&username, &client) {
+        return (StatusCode::FORBIDDEN, empty());
+    }
+    let filename = format!("{}.json", username);
+    let file = filename.clone();
+    let tabs = run_blocking(move || read_history(&file, timestamp)).await
+        .and_then(|contents| contents.map(|contents| serde_json::from_str::<Vec<TabGroup>>(&contents)).transpose().map_err(std::io::Error::from));
+    match tabs {
+        Ok(None) => (StatusCode::NOT_FOUND, empty()),
+        Ok(Some(tabs)) => (StatusCode::OK, Json(Tabs {
+            tabs,
+            token: "".to_string(),
+            label: LABELS.lock().unwrap().get(&filename, timestamp).cloned(),
+        })),
+        Err(e) => {
+            error!("Error reading snapshot {} of {}: {}", timestamp, username, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, empty())
+        }
+    }
+}
+
+/// Snapshots the current tabs on demand, optionally labeled.
+async fn create_history_snapshot(
+    Extension(client): Extension<ClientInfo>, Path(username): Path<String>, Json(payload): Json<SnapshotRequest>
+) -> (StatusCode, Json<String>) {
+    if !try_get_username_token(&username, payload.token.to_string()) {
+        return (StatusCode::UNAUTHORIZED, Json("Not found token".to_string()));
+    }
+    if !user_region_lock_allows(&username, &client) {
+        return (StatusCode::FORBIDDEN, Json(format!("Forbidden region: {}", client.region)));
+    }
+    if payload.label.as_deref().is_some_and(|label| !is_valid_label(label)) {
+        return (StatusCode::BAD_REQUEST, Json("Invalid label".to_string()));
+    }
+    let filename = format!("{}.json", username);
+    match run_blocking(move || create_snapshot(&filename, payload.label)).await {
+        Ok(Some(timestamp)) => (StatusCode::OK, Json(timestamp.to_string())),
+        Ok(None) => (StatusCode::NOT_FOUND, Json("No tabs".to_string())),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(format!("Error saving snapshot: {}", e))),
+    }
+}
+
+/// Sets or removes the label of a snapshot.
+async fn label_history_snapshot(
+    Extension(client): Extension<ClientInfo>, Path((username, timestamp)): Path<(String, i64)>, Json(payload): Json<SnapshotRequest>
+) -> (StatusCode, Json<String>) {
+    if !try_get_username_token(&username, payload.token.to_string()) {
+        return (StatusCode::UNAUTHORIZED, Json("Not found token".to_string()));
+    }
+    if !user_region_lock_allows(&username, &client) {
+        return (StatusCode::FORBIDDEN, Json(format!("Forbidden region: {}", client.region)));
+    }
+    if payload.label.as_deref().is_some_and(|label| !is_valid_label(label)) {
+        return (StatusCode::BAD_REQUEST, Json("Invalid label".to_string()));
+    }
+    let filename = format!("{}.json", username);
+    if !read_history(&filename, timestamp).is_ok_and(|contents| contents.is_some()) {
+        return (StatusCode::NOT_FOUND, Json(format!("No snapshot {}", timestamp)));
+    }
+    match LABELS.lock().unwrap().set(&filename, timestamp, payload.label) {
+        Ok(()) => (StatusCode::OK, Json("OK".to_string())),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(format!("Error saving labels: {}", e))),
+    }
+}
+
+// test module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_region_lock_rejects_injected_lines() {
+        let client = ClientInfo { ip: None, region: String::from("SG") };
+        let payload = RegionLock { token: String::from("token"), regions: vec![String::from("SG"), String::from("SG\nadmin,pw")] };
+        let (status, Json(message)) = update_region_lock(Extension(client), Path(String::from("alice")), Json(payload)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(message, r#"regions[1]: "SG\nadmin,pw" is not an ISO 3166 alpha-2 region code"#);
+    }
+}
@@ -0,0 +1,18 @@
+use serde::{Deserialize, Serialize};
+use crate::history::SnapshotMeta;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotRequest {
+    pub token: String,
+    // a label exempts the snapshot from pruning, none or null removes it
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotInfo {
+    pub timestamp: i64,
+    pub size: u64,
+    pub label: Option<String>,
+    pub meta: Option<SnapshotMeta>,
+}
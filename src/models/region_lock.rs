@@ -0,0 +1,8 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RegionLock {
+    pub token: String,
+    // empty list removes the lock
+    pub regions: Vec<String>,
+}
@@ -3,9 +3,13 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Tabs {
     pub tabs: Vec<TabGroup>,
-    pub token: String
+    pub token: String,
+    // labels a snapshot of the uploaded tabs, labeled snapshots are never pruned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
 }
 #[derive(Serialize, Deserialize, Debug)]
+#[allow(non_snake_case)]
 pub struct TabGroup {
     #[serde(rename = "_id")]
     pub id: String,
@@ -23,6 +27,7 @@ pub struct TabGroup {
 
 
 #[derive(Serialize, Deserialize, Debug)]
+#[allow(non_snake_case)]
 pub struct Tab {
     pub uuid : String,
     pub favIconUrl: String,
@@ -6,4 +6,8 @@ use serde::{Deserialize, Serialize};
 pub struct User {
     pub username: String,
     pub password: String,
-}
\ No newline at end of file
+    // regions the user allows their account to be used from, empty means no lock.
+    // stored as the optional third column of users.txt, `SG|MY`
+    #[serde(default, skip_serializing)]
+    pub region_lock: Vec<String>,
+}
@@ -0,0 +1,429 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use log::{info, warn};
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use crate::config::{OidcSettings, STABLE_OIDC_CLAIMS};
+use crate::util::{data_path, generate_random_string, write_atomic};
+
+// pending logins older than this are dropped
+const PENDING_LOGIN_TTL_SECONDS: i64 = 10 * 60;
+// the login endpoint is public, so the pending logins are capped
+const MAX_PENDING_LOGINS: usize = 10_000;
+// provider metadata is fetched again after this
+const METADATA_TTL_SECONDS: i64 = 60 * 60;
+// users who signed in through the provider, they have a token but no users.txt line
+pub const OIDC_USERS_FILE: &str = "oidc/users.txt";
+
+lazy_static! {
+    static ref PENDING_LOGINS: Mutex<HashMap<String, PendingLogin>> = Mutex::new(HashMap::new());
+    // by issuer, with the time it was fetched
+    static ref PROVIDER_METADATA: Mutex<HashMap<String, (i64, ProviderMetadata)>> = Mutex::new(HashMap::new());
+    static ref OIDC_USERS_LOCK: Mutex<()> = Mutex::new(());
+}
+
+struct PendingLogin {
+    code_verifier: String,
+    nonce: String,
+    created_at: i64,
+}
+
+#[derive(Deserialize, Clone)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+async fn fetch_provider_metadata(settings: &OidcSettings) -> Result<ProviderMetadata, String> {
+    let url = format!("{}/.well-known/openid-configuration", settings.issuer.trim_end_matches('/'));
+    let response = reqwest::get(&url).await
+        .map_err(|e| format!("Error fetching {}: {}", url, e))?;
+    if !response.status().is_success() {
+        return Err(format!("Error fetching {}: status {}", url, response.status()));
+    }
+    let metadata: ProviderMetadata = response.json().await
+        .map_err(|e| format!("Error parsing {}: {}", url, e))?;
+    if metadata.issuer.trim_end_matches('/') != settings.issuer.trim_end_matches('/') {
+        return Err(format!("Issuer mismatch: expected {}, got {}", settings.issuer, metadata.issuer));
+    }
+    Ok(metadata)
+}
+
+/// The provider metadata, fetched at most once per `METADATA_TTL_SECONDS`.
+async fn provider_metadata(settings: &OidcSettings) -> Result<ProviderMetadata, String> {
+    let now = chrono::Utc::now().timestamp();
+    if let Some((fetched_at, metadata)) = PROVIDER_METADATA.lock().unwrap().get(&settings.issuer) {
+        if now - fetched_at < METADATA_TTL_SECONDS {
+            return Ok(metadata.clone());
+        }
+    }
+    let metadata = fetch_provider_metadata(settings).await?;
+    PROVIDER_METADATA.lock().unwrap().insert(settings.issuer.clone(), (now, metadata.clone()));
+    Ok(metadata)
+}
+
+fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+fn take_pending_login(state: &str) -> Option<PendingLogin> {
+    let now = chrono::Utc::now().timestamp();
+    let mut pending_logins = PENDING_LOGINS.lock().unwrap();
+    pending_logins.retain(|_, login| now - login.created_at <= PENDING_LOGIN_TTL_SECONDS);
+    pending_logins.remove(state)
+}
+
+/// Adds `login`, dropping expired logins when the map is full. Returns false when
+/// `MAX_PENDING_LOGINS` are still pending.
+fn add_pending_login(pending_logins: &mut HashMap<String, PendingLogin>, state: String, login: PendingLogin) -> bool {
+    if pending_logins.len() >= MAX_PENDING_LOGINS {
+        pending_logins.retain(|_, pending| login.created_at - pending.created_at <= PENDING_LOGIN_TTL_SECONDS);
+    }
+    if pending_logins.len() >= MAX_PENDING_LOGINS {
+        return false;
+    }
+    pending_logins.insert(state, login);
+    true
+}
+
+/// Builds the authorization url the browser is redirected to, and remembers the
+/// state, nonce and PKCE verifier until the provider calls back. `None` when too many
+/// logins are pending.
+pub async fn begin_login(settings: &OidcSettings) -> Result<Option<String>, String> {
+    let metadata = provider_metadata(settings).await?;
+
+    let state = generate_random_string(32);
+    let nonce = generate_random_string(32);
+    let code_verifier = generate_random_string(64);
+    let code_challenge = pkce_challenge(&code_verifier);
+
+    let url = Url::parse_with_params(&metadata.authorization_endpoint, &[
+        ("response_type", "code"),
+        ("client_id", settings.client_id.as_str()),
+        ("redirect_uri", settings.redirect_uri.as_str()),
+        ("scope", settings.scopes.join(" ").as_str()),
+        ("state", state.as_str()),
+        ("nonce", nonce.as_str()),
+        ("code_challenge", code_challenge.as_str()),
+        ("code_challenge_method", "S256"),
+    ]).map_err(|e| format!("Invalid authorization endpoint {}: {}", metadata.authorization_endpoint, e))?;
+
+    let login = PendingLogin {
+        code_verifier,
+        nonce,
+        created_at: chrono::Utc::now().timestamp(),
+    };
+    if !add_pending_login(&mut PENDING_LOGINS.lock().unwrap(), state, login) {
+        warn!("{} OIDC logins are pending, rejecting new ones", MAX_PENDING_LOGINS);
+        return Ok(None);
+    }
+    Ok(Some(url.to_string()))
+}
+
+/// Exchanges the authorization code for an id token and maps its claims to a local username.
+pub async fn complete_login(settings: &OidcSettings, code: &str, state: &str) -> Result<String, String> {
+    let pending_login = match take_pending_login(state) {
+        Some(value) => value,
+        None => return Err("Unknown or expired login state".to_string()),
+    };
+    let metadata = provider_metadata(settings).await?;
+
+    let response = reqwest::Client::new()
+        .post(&metadata.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", settings.redirect_uri.as_str()),
+            ("client_id", settings.client_id.as_str()),
+            ("client_secret", settings.client_secret.as_str()),
+            ("code_verifier", pending_login.code_verifier.as_str()),
+        ])
+        .send().await
+        .map_err(|e| format!("Error calling token endpoint: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!("Token endpoint returned status {}", response.status()));
+    }
+    let token_response: TokenResponse = response.json().await
+        .map_err(|e| format!("Error parsing token response: {}", e))?;
+
+    // the id token comes straight from the token endpoint over our own connection,
+    // so TLS authenticates the issuer and the signature is not checked again (OIDC core 3.1.3.7)
+    let claims = decode_id_token_claims(&token_response.id_token)?;
+    validate_claims(settings, &metadata, &claims, &pending_login.nonce)?;
+
+    let username = map_username(settings, &claims)?;
+    info!("OIDC login for {} from {}", username, metadata.issuer);
+    Ok(username)
+}
+
+fn decode_id_token_claims(id_token: &str) -> Result<serde_json::Value, String> {
+    let parts: Vec<&str> = id_token.split('.').collect();
+    if parts.len() != 3 {
+        return Err("Malformed id token".to_string());
+    }
+    let payload = URL_SAFE_NO_PAD.decode(parts[1].trim_end_matches('='))
+        .map_err(|e| format!("Malformed id token payload: {}", e))?;
+    serde_json::from_slice(&payload).map_err(|e| format!("Malformed id token claims: {}", e))
+}
+
+fn validate_claims(settings: &OidcSettings, metadata: &ProviderMetadata, claims: &serde_json::Value, nonce: &str) -> Result<(), String> {
+    if claims["iss"].as_str() != Some(metadata.issuer.as_str()) {
+        return Err(format!("Unexpected id token issuer: {}", claims["iss"]));
+    }
+    let audience_matches = match &claims["aud"] {
+        serde_json::Value::String(aud) => aud == &settings.client_id,
+        serde_json::Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(settings.client_id.as_str())),
+        _ => false,
+    };
+    if !audience_matches {
+        return Err(format!("Unexpected id token audience: {}", claims["aud"]));
+    }
+    match claims["exp"].as_i64() {
+        Some(exp) if exp > chrono::Utc::now().timestamp() => {}
+        _ => return Err("Id token is expired".to_string()),
+    }
+    if claims["nonce"].as_str() != Some(nonce) {
+        return Err("Id token nonce mismatch".to_string());
+    }
+    Ok(())
+}
+
+/// Without a username map only a claim the user can not pick at the provider names a local
+/// user, otherwise anyone could take over a local user by renaming their provider account.
+fn map_username(settings: &OidcSettings, claims: &serde_json::Value) -> Result<String, String> {
+    let claim_value = match claims[settings.username_claim.as_str()].as_str() {
+        Some(value) => value,
+        None => return Err(format!("Id token has no {} claim", settings.username_claim)),
+    };
+    if settings.username_claim == "email" && claims["email_verified"].as_bool() != Some(true) {
+        return Err(format!("Email {} is not verified", claim_value));
+    }
+    let username = if settings.username_map.is_empty() {
+        if !STABLE_OIDC_CLAIMS.contains(&settings.username_claim.as_str()) {
+            return Err(format!("{} can not be used as username without username_map", settings.username_claim));
+        }
+        claim_value.to_string()
+    } else {
+        match settings.username_map.get(claim_value) {
+            Some(value) => value.clone(),
+            None => {
+                warn!("OIDC identity {} is not mapped to a user", claim_value);
+                return Err(format!("No user mapped for {}", claim_value));
+            }
+        }
+    };
+    if !is_valid_username(&username) {
+        return Err(format!("Invalid username: {}", username));
+    }
+    Ok(username)
+}
+
+/// Usernames end up in file names under the data directory, so keep them to a safe charset.
+/// Usernames of an OIDC users file, one per line, none when it is missing.
+pub fn read_oidc_users(path: &Path) -> Result<HashSet<String>, std::io::Error> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents.lines().filter(|line| !line.is_empty()).map(String::from).collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Records a user who signed in through the provider, so fsck knows whose token it is.
+pub fn record_oidc_user(username: &str) -> Result<(), std::io::Error> {
+    let _guard = OIDC_USERS_LOCK.lock().unwrap();
+    let path = data_path(OIDC_USERS_FILE);
+    let mut users = read_oidc_users(&path)?;
+    if !users.insert(username.to_string()) {
+        return Ok(());
+    }
+    let mut users = users.into_iter().collect::<Vec<String>>();
+    users.sort();
+    fs::create_dir_all(path.parent().unwrap())?;
+    write_atomic(&path, users.iter().map(|user| format!("{}\n", user)).collect::<String>().as_bytes())
+}
+
+pub fn is_valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && !username.starts_with('.')
+        && username.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' || c == '@')
+}
+
+// test module
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use axum::{Form, Json, Router};
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use axum::routing::{get, post};
+    use super::*;
+
+    #[derive(Default)]
+    struct MockProvider {
+        issuer: String,
+        code_challenge: String,
+        nonce: String,
+        // claims of the logged in identity
+        identity: serde_json::Value,
+        discovery_requests: usize,
+    }
+
+    type MockState = Arc<Mutex<MockProvider>>;
+
+    async fn discovery(State(state): State<MockState>) -> Json<serde_json::Value> {
+        let issuer = {
+            let mut provider = state.lock().unwrap();
+            provider.discovery_requests += 1;
+            provider.issuer.clone()
+        };
+        Json(serde_json::json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+        }))
+    }
+
+    async fn token(State(state): State<MockState>, Form(params): Form<HashMap<String, String>>) -> (StatusCode, Json<serde_json::Value>) {
+        let provider = state.lock().unwrap();
+        let verifier = params.get("code_verifier").cloned().unwrap_or_default();
+        if params.get("code").map(String::as_str) != Some("test-code")
+            || params.get("client_secret").map(String::as_str) != Some("secret")
+            || pkce_challenge(&verifier) != provider.code_challenge {
+            return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error": "invalid_grant"})));
+        }
+        let mut claims = serde_json::json!({
+            "iss": provider.issuer,
+            "aud": "tabs",
+            "exp": chrono::Utc::now().timestamp() + 60,
+            "nonce": provider.nonce,
+        });
+        for (name, value) in provider.identity.as_object().unwrap() {
+            claims[name] = value.clone();
+        }
+        let id_token = format!("{}.{}.", URL_SAFE_NO_PAD.encode(br#"{"alg":"none"}"#), URL_SAFE_NO_PAD.encode(claims.to_string()));
+        (StatusCode::OK, Json(serde_json::json!({"id_token": id_token, "access_token": "access", "token_type": "Bearer"})))
+    }
+
+    async fn start_mock_provider(identity: serde_json::Value) -> (OidcSettings, MockState) {
+        let state: MockState = Arc::new(Mutex::new(MockProvider::default()));
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/token", post(token))
+            .with_state(state.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        {
+            let mut provider = state.lock().unwrap();
+            provider.issuer = issuer.clone();
+            provider.identity = identity;
+        }
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let settings = OidcSettings {
+            issuer,
+            client_id: "tabs".to_string(),
+            client_secret: "secret".to_string(),
+            redirect_uri: "http://localhost:9401/api/oidc/callback".to_string(),
+            scopes: vec!["openid".to_string(), "profile".to_string()],
+            username_claim: "sub".to_string(),
+            username_map: HashMap::new(),
+        };
+        (settings, state)
+    }
+
+    // plays the browser: follows the authorization url and hands the provider what it would have seen
+    async fn authorize(settings: &OidcSettings, state: &MockState) -> String {
+        let url = Url::parse(&begin_login(settings).await.unwrap().unwrap()).unwrap();
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["code_challenge_method"], "S256");
+        let mut provider = state.lock().unwrap();
+        provider.code_challenge = params["code_challenge"].clone();
+        provider.nonce = params["nonce"].clone();
+        params["state"].clone()
+    }
+
+    #[tokio::test]
+    async fn test_login_flow() {
+        let (settings, state) = start_mock_provider(serde_json::json!({ "sub": "alice" })).await;
+        let login_state = authorize(&settings, &state).await;
+        let username = complete_login(&settings, "test-code", &login_state).await.unwrap();
+        assert_eq!(username, "alice");
+        // the state is single use
+        assert!(complete_login(&settings, "test-code", &login_state).await.is_err());
+        // the metadata is cached
+        assert_eq!(state.lock().unwrap().discovery_requests, 1);
+    }
+
+    #[test]
+    fn test_pending_logins_are_capped() {
+        let login = |created_at| PendingLogin { code_verifier: String::new(), nonce: String::new(), created_at };
+        let mut pending_logins = HashMap::new();
+        for i in 0..MAX_PENDING_LOGINS {
+            assert!(add_pending_login(&mut pending_logins, i.to_string(), login(1000)));
+        }
+        assert!(!add_pending_login(&mut pending_logins, String::from("full"), login(1000)));
+        // expired ones make room
+        assert!(add_pending_login(&mut pending_logins, String::from("later"), login(1000 + PENDING_LOGIN_TTL_SECONDS + 1)));
+        assert_eq!(pending_logins.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_state() {
+        let (settings, state) = start_mock_provider(serde_json::json!({ "sub": "alice" })).await;
+        authorize(&settings, &state).await;
+        assert!(complete_login(&settings, "test-code", "forged-state").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_username_map() {
+        let (mut settings, state) = start_mock_provider(serde_json::json!({ "preferred_username": "alice@example.com" })).await;
+        settings.username_claim = "preferred_username".to_string();
+        settings.username_map.insert("bob@example.com".to_string(), "bob".to_string());
+        let login_state = authorize(&settings, &state).await;
+        assert!(complete_login(&settings, "test-code", &login_state).await.is_err());
+
+        settings.username_map.insert("alice@example.com".to_string(), "alice".to_string());
+        let login_state = authorize(&settings, &state).await;
+        assert_eq!(complete_login(&settings, "test-code", &login_state).await.unwrap(), "alice");
+    }
+
+    #[tokio::test]
+    async fn test_editable_claim_needs_username_map() {
+        // a provider account renamed to a local username
+        let (mut settings, state) = start_mock_provider(serde_json::json!({ "sub": "1234", "preferred_username": "alice" })).await;
+        settings.username_claim = "preferred_username".to_string();
+        let login_state = authorize(&settings, &state).await;
+        assert!(complete_login(&settings, "test-code", &login_state).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_email_must_be_verified() {
+        let (mut settings, state) = start_mock_provider(serde_json::json!({ "email": "alice@example.com", "email_verified": false })).await;
+        settings.username_claim = "email".to_string();
+        let login_state = authorize(&settings, &state).await;
+        assert_eq!(complete_login(&settings, "test-code", &login_state).await, Err("Email alice@example.com is not verified".to_string()));
+
+        state.lock().unwrap().identity["email_verified"] = serde_json::Value::Bool(true);
+        let login_state = authorize(&settings, &state).await;
+        assert_eq!(complete_login(&settings, "test-code", &login_state).await.unwrap(), "alice@example.com");
+    }
+
+    #[test]
+    fn test_is_valid_username() {
+        assert!(is_valid_username("alice@example.com"));
+        assert!(!is_valid_username("../users"));
+        assert!(!is_valid_username("a/b"));
+        assert!(!is_valid_username(""));
+    }
+}
@@ -0,0 +1,248 @@
+use std::fmt;
+use std::net::IpAddr;
+use axum::http::Method;
+use ipnet::IpNet;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use crate::config::PolicyAction;
+
+fn default_policy_action() -> PolicyAction {
+    PolicyAction::Deny
+}
+
+/// Access rules for a client, evaluated in this order, the first match wins:
+/// 1. `deny_cidrs`
+/// 2. `allow_cidrs`
+/// 3. `deny_lists`
+/// 4. `deny_asns`
+/// 5. `allow_asns`
+/// 6. `deny_regions`
+/// 7. `allow_regions`
+/// 8. `default_action`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RegionPolicy {
+    #[serde(default = "default_policy_action")]
+    pub default_action: PolicyAction,
+    #[serde(default)]
+    #[schemars(inner(regex(pattern = r"^([A-Z]{2}|unknown)$")))]
+    pub allow_regions: Vec<String>,
+    #[serde(default)]
+    #[schemars(inner(regex(pattern = r"^([A-Z]{2}|unknown)$")))]
+    pub deny_regions: Vec<String>,
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub allow_cidrs: Vec<IpNet>,
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub deny_cidrs: Vec<IpNet>,
+    #[serde(default)]
+    pub allow_asns: Vec<u32>,
+    #[serde(default)]
+    pub deny_asns: Vec<u32>,
+    // names of `ip_lists`
+    #[serde(default)]
+    pub deny_lists: Vec<String>,
+}
+
+/// ISO 3166 alpha-2 codes as found in the GeoIP files, or `unknown` for addresses not found.
+pub fn is_region_code(code: &str) -> bool {
+    code == "unknown" || (code.len() == 2 && code.chars().all(|c| c.is_ascii_uppercase()))
+}
+
+/// Invalid region codes of `codes`, with their json path.
+pub fn region_code_violations(path: &str, codes: &[String]) -> Vec<String> {
+    codes.iter().enumerate()
+        .filter(|(_, code)| !is_region_code(code))
+        .map(|(index, code)| format!("{}[{}]: {:?} is not an ISO 3166 alpha-2 region code", path, index, code))
+        .collect()
+}
+
+/// What is known about a client when a policy is evaluated.
+pub struct PolicyInput<'a> {
+    pub ip: IpAddr,
+    pub region: &'a str,
+    pub asn: Option<u32>,
+    // names of the ip lists containing the ip
+    pub lists: &'a [String],
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Decision {
+    pub action: PolicyAction,
+    // the rule that matched
+    pub rule: String,
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} by {}", self.action, self.rule)
+    }
+}
+
+impl RegionPolicy {
+    pub fn violations(&self, path: &str) -> Vec<String> {
+        let mut violations = region_code_violations(&format!("{}.allow_regions", path), &self.allow_regions);
+        violations.extend(region_code_violations(&format!("{}.deny_regions", path), &self.deny_regions));
+        violations
+    }
+
+    /// Same behavior as the plain `white_region_code_list`: listed regions are allowed, everything else is denied.
+    pub fn from_white_list(white_region_code_list: &[String]) -> Self {
+        RegionPolicy {
+            default_action: PolicyAction::Deny,
+            allow_regions: white_region_code_list.to_vec(),
+            deny_regions: Vec::new(),
+            allow_cidrs: Vec::new(),
+            deny_cidrs: Vec::new(),
+            allow_asns: Vec::new(),
+            deny_asns: Vec::new(),
+            deny_lists: Vec::new(),
+        }
+    }
+
+    pub fn evaluate(&self, input: &PolicyInput) -> Decision {
+        let region = input.region;
+        if let Some(net) = self.deny_cidrs.iter().find(|net| net.contains(&input.ip)) {
+            return Decision { action: PolicyAction::Deny, rule: format!("deny_cidrs {}", net) };
+        }
+        if let Some(net) = self.allow_cidrs.iter().find(|net| net.contains(&input.ip)) {
+            return Decision { action: PolicyAction::Allow, rule: format!("allow_cidrs {}", net) };
+        }
+        if let Some(list) = self.deny_lists.iter().find(|list| input.lists.contains(list)) {
+            return Decision { action: PolicyAction::Deny, rule: format!("deny_lists {}", list) };
+        }
+        if let Some(asn) = input.asn {
+            if self.deny_asns.contains(&asn) {
+                return Decision { action: PolicyAction::Deny, rule: format!("deny_asns {}", asn) };
+            }
+            if self.allow_asns.contains(&asn) {
+                return Decision { action: PolicyAction::Allow, rule: format!("allow_asns {}", asn) };
+            }
+        }
+        if self.deny_regions.iter().any(|deny_region| deny_region == region) {
+            return Decision { action: PolicyAction::Deny, rule: format!("deny_regions {}", region) };
+        }
+        if self.allow_regions.iter().any(|allow_region| allow_region == region) {
+            return Decision { action: PolicyAction::Allow, rule: format!("allow_regions {}", region) };
+        }
+        Decision { action: self.default_action, rule: "default_action".to_string() }
+    }
+}
+
+/// Route groups `route_policies` can be attached to.
+pub const ROUTE_GROUPS: [&str; 5] = ["root", "login", "read", "write", "admin"];
+
+pub fn route_group(method: &Method, path: &str) -> &'static str {
+    if path.starts_with("/api/admin/") {
+        "admin"
+    } else if path == "/api/verify" || path.starts_with("/api/oidc/") {
+        "login"
+    } else if path.starts_with("/api/user/") {
+        if method == Method::GET || method == Method::HEAD { "read" } else { "write" }
+    } else {
+        "root"
+    }
+}
+
+// test module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RegionPolicy {
+        RegionPolicy {
+            default_action: PolicyAction::Deny,
+            allow_regions: vec!["SG".to_string(), "MY".to_string()],
+            deny_regions: vec!["MY".to_string()],
+            allow_cidrs: vec!["203.0.113.0/24".parse().unwrap(), "10.0.0.0/8".parse().unwrap()],
+            deny_cidrs: vec!["10.6.6.0/24".parse().unwrap()],
+            allow_asns: vec![64500],
+            deny_asns: vec![16509],
+            deny_lists: vec!["tor".to_string()],
+        }
+    }
+
+    fn evaluate(policy: &RegionPolicy, ip: &str, region: &str, asn: Option<u32>, lists: &[String]) -> Decision {
+        policy.evaluate(&PolicyInput { ip: ip.parse().unwrap(), region, asn, lists })
+    }
+
+    fn action(policy: &RegionPolicy, ip: &str, region: &str) -> PolicyAction {
+        evaluate(policy, ip, region, None, &[]).action
+    }
+
+    #[test]
+    fn test_region_rules() {
+        let policy = policy();
+        assert_eq!(action(&policy, "198.51.100.1", "SG"), PolicyAction::Allow);
+        assert_eq!(action(&policy, "198.51.100.1", "ES"), PolicyAction::Deny);
+        assert_eq!(action(&policy, "198.51.100.1", "unknown"), PolicyAction::Deny);
+    }
+
+    #[test]
+    fn test_deny_region_beats_allow_region() {
+        assert_eq!(action(&policy(), "198.51.100.1", "MY"), PolicyAction::Deny);
+    }
+
+    #[test]
+    fn test_allow_cidr_beats_regions() {
+        let policy = policy();
+        assert_eq!(action(&policy, "203.0.113.9", "unknown"), PolicyAction::Allow);
+        assert_eq!(action(&policy, "203.0.113.9", "MY"), PolicyAction::Allow);
+    }
+
+    #[test]
+    fn test_deny_cidr_beats_allow_cidr() {
+        let policy = policy();
+        assert_eq!(action(&policy, "10.6.6.1", "SG"), PolicyAction::Deny);
+        assert_eq!(action(&policy, "10.7.7.1", "ES"), PolicyAction::Allow);
+    }
+
+    #[test]
+    fn test_default_action() {
+        let mut policy = policy();
+        policy.default_action = PolicyAction::Allow;
+        assert_eq!(action(&policy, "198.51.100.1", "ES"), PolicyAction::Allow);
+        assert_eq!(evaluate(&policy, "198.51.100.1", "ES", None, &[]).rule, "default_action");
+    }
+
+    #[test]
+    fn test_deny_asn_beats_allow_region() {
+        let decision = evaluate(&policy(), "198.51.100.1", "SG", Some(16509), &[]);
+        assert_eq!(decision.action, PolicyAction::Deny);
+        assert_eq!(decision.rule, "deny_asns 16509");
+    }
+
+    #[test]
+    fn test_allow_asn_beats_deny_region() {
+        assert_eq!(evaluate(&policy(), "198.51.100.1", "MY", Some(64500), &[]).action, PolicyAction::Allow);
+        assert_eq!(evaluate(&policy(), "198.51.100.1", "ES", Some(64500), &[]).action, PolicyAction::Allow);
+    }
+
+    #[test]
+    fn test_deny_list() {
+        let tor = vec!["tor".to_string()];
+        assert_eq!(evaluate(&policy(), "198.51.100.1", "SG", Some(64500), &tor).action, PolicyAction::Deny);
+        // an explicit CIDR still wins
+        assert_eq!(evaluate(&policy(), "203.0.113.9", "SG", None, &tor).action, PolicyAction::Allow);
+        let hosting = vec!["hosting".to_string()];
+        assert_eq!(evaluate(&policy(), "198.51.100.1", "SG", None, &hosting).action, PolicyAction::Allow);
+    }
+
+    #[test]
+    fn test_route_group() {
+        assert_eq!(route_group(&Method::GET, "/"), "root");
+        assert_eq!(route_group(&Method::POST, "/api/verify"), "login");
+        assert_eq!(route_group(&Method::GET, "/api/oidc/callback"), "login");
+        assert_eq!(route_group(&Method::GET, "/api/user/alice/tabs"), "read");
+        assert_eq!(route_group(&Method::POST, "/api/user/alice/tabs"), "write");
+        assert_eq!(route_group(&Method::POST, "/api/admin/geoip/reload"), "admin");
+    }
+
+    #[test]
+    fn test_from_white_list() {
+        let policy = RegionPolicy::from_white_list(&["SG".to_string()]);
+        assert_eq!(action(&policy, "198.51.100.1", "SG"), PolicyAction::Allow);
+        assert_eq!(action(&policy, "198.51.100.1", "unknown"), PolicyAction::Deny);
+    }
+}
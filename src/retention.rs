@@ -0,0 +1,598 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use log::{error, info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use crate::config::{HistoryBackend, CONFIG_INSTANCE};
+use crate::git_history;
+use crate::history::{collect_garbage, lock_store, object_path, object_sizes, read_manifest, read_meta, remove_snapshot, SnapshotMeta, MANIFEST_EXTENSION, META_EXTENSION};
+use crate::labels::{Labels, LABELS};
+use crate::util::{data_path, lock_file};
+
+lazy_static! {
+    pub static ref RETENTION_METRICS: Mutex<RetentionMetrics> = Mutex::new(RetentionMetrics::default());
+    // one run at a time, scheduled or requested
+    static ref RUNNING: Mutex<()> = Mutex::new(());
+}
+
+/// History retention rules, all of them apply together. Labeled snapshots are never removed
+/// and do not count for `min_keep` and `max_keep`. In order of precedence:
+/// 1. `min_keep`: the newest snapshots of a user are always kept
+/// 2. `max_keep`: snapshots of a user beyond the newest `max_keep` are removed
+/// 3. `max_age`: snapshots older than `max_age` days are removed
+/// 4. `tiered`: keeps every snapshot of the last day, then one per hour for a week,
+///    one per day for a month and one per week for a year
+/// 5. `max_size`: the oldest snapshots are removed until the history directory fits
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionSettings {
+    #[serde(default)]
+    pub min_keep: u32,
+    #[serde(default)]
+    pub max_keep: Option<u32>,
+    // by days
+    #[serde(default)]
+    #[schemars(range(min = 1, max = 36500))]
+    pub max_age: Option<u32>,
+    #[serde(default)]
+    pub tiered: bool,
+    // by MB, for the whole history directory
+    #[serde(default)]
+    #[schemars(range(min = 1, max = 1048576))]
+    pub max_size: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub enum Rule {
+    #[serde(rename = "max_keep")]
+    Count,
+    #[serde(rename = "max_age")]
+    Age,
+    #[serde(rename = "tiered")]
+    Tiered,
+    #[serde(rename = "max_size")]
+    Size,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rule::Count => write!(f, "max_keep"),
+            Rule::Age => write!(f, "max_age"),
+            Rule::Tiered => write!(f, "tiered"),
+            Rule::Size => write!(f, "max_size"),
+        }
+    }
+}
+
+const HOUR: i64 = 60 * 60;
+const DAY: i64 = 24 * HOUR;
+
+// up to this age, snapshots are thinned to one per bucket of this length, newest wins
+const TIERS: [(i64, i64); 4] = [(DAY, 1), (7 * DAY, HOUR), (30 * DAY, DAY), (365 * DAY, 7 * DAY)];
+
+/// One file in a `history/<unix time>/` directory.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Snapshot {
+    pub timestamp: i64,
+    pub filename: String,
+    // of the manifest or the plain copy
+    pub size: u64,
+    pub label: Option<String>,
+    // from the sidecar, missing for snapshots taken before sidecars existed
+    pub meta: Option<SnapshotMeta>,
+    // ids and sizes of the objects of the manifest, shared with other snapshots
+    #[serde(skip)]
+    pub objects: Vec<(String, u64)>,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Decision {
+    pub snapshot: Snapshot,
+    // the rule removing the snapshot, or for kept snapshots the rule that would have removed it
+    pub rule: Rule,
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct RetentionReport {
+    pub dry_run: bool,
+    pub kept: usize,
+    pub removed: Vec<Decision>,
+    // snapshots a rule would remove but min_keep keeps
+    pub protected: Vec<Decision>,
+    pub bytes_freed: u64,
+    // git history: squashed away but on disk until `git gc`
+    pub bytes_unreferenced: u64,
+}
+
+/// Counters of the retention runs since startup, dry runs are not counted.
+#[derive(Debug, Serialize, Default, Clone)]
+pub struct RetentionMetrics {
+    pub runs: u64,
+    pub last_run_at: Option<i64>,
+    pub last_duration_ms: u64,
+    pub last_removed: usize,
+    pub last_bytes_freed: u64,
+    pub last_error: Option<String>,
+    pub total_removed: u64,
+    pub total_bytes_freed: u64,
+}
+
+impl RetentionMetrics {
+    fn record(&mut self, result: &Result<RetentionReport, std::io::Error>, started_at: i64, duration: Duration) {
+        self.runs += 1;
+        self.last_run_at = Some(started_at);
+        self.last_duration_ms = duration.as_millis() as u64;
+        match result {
+            Ok(report) => {
+                self.last_removed = report.removed.len();
+                self.last_bytes_freed = report.bytes_freed;
+                self.last_error = None;
+                self.total_removed += report.removed.len() as u64;
+                self.total_bytes_freed += report.bytes_freed;
+            }
+            Err(e) => {
+                self.last_removed = 0;
+                self.last_bytes_freed = 0;
+                self.last_error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+impl fmt::Display for RetentionReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let verb = if self.dry_run { "would remove" } else { "removed" };
+        for decision in &self.removed {
+            writeln!(f, "{} {}/{} ({} bytes), {}", verb, decision.snapshot.timestamp, decision.snapshot.filename, decision.snapshot.size, decision.rule)?;
+        }
+        for decision in &self.protected {
+            writeln!(f, "keep {}/{}, {} is overruled by min_keep", decision.snapshot.timestamp, decision.snapshot.filename, decision.rule)?;
+        }
+        write!(f, "{} {} snapshots, {} bytes, kept {}", verb, self.removed.len(), self.bytes_freed, self.kept)?;
+        if self.bytes_unreferenced > 0 {
+            write!(f, ", {} bytes unreferenced until git gc", self.bytes_unreferenced)?;
+        }
+        Ok(())
+    }
+}
+
+impl RetentionSettings {
+    pub fn violations(&self, path: &str) -> Vec<String> {
+        let mut violations = Vec::new();
+        if self.max_keep.is_some_and(|max_keep| max_keep < self.min_keep) {
+            violations.push(format!("{}.max_keep: must not be below min_keep {}", path, self.min_keep));
+        }
+        // turned into seconds and bytes
+        for (name, value, max) in [("max_age", self.max_age, 36500), ("max_size", self.max_size, 1024 * 1024)] {
+            if value.is_some_and(|value| value == 0 || value > max) {
+                violations.push(format!("{}.{}: {} is not between 1 and {}", path, name, value.unwrap(), max));
+            }
+        }
+        violations
+    }
+
+    /// Decides which snapshots to remove, snapshots in any order.
+    pub fn plan(&self, snapshots: &[Snapshot], now: i64) -> RetentionReport {
+        let mut snapshots = snapshots.to_vec();
+        // newest first
+        snapshots.sort_by(|a, b| b.timestamp.cmp(&a.timestamp).then(a.filename.cmp(&b.filename)));
+
+        let mut rules: Vec<Option<Rule>> = vec![None; snapshots.len()];
+        let mut protected = vec![false; snapshots.len()];
+        let mut per_user: HashMap<&str, u32> = HashMap::new();
+        let mut tier_buckets: HashSet<(&str, usize, i64)> = HashSet::new();
+        for (index, snapshot) in snapshots.iter().enumerate() {
+            if snapshot.label.is_some() {
+                continue;
+            }
+            let position = per_user.entry(snapshot.filename.as_str()).or_insert(0);
+            protected[index] = *position < self.min_keep;
+            let age = now - snapshot.timestamp;
+            if self.max_keep.is_some_and(|max_keep| *position >= max_keep) {
+                rules[index] = Some(Rule::Count);
+            } else if self.max_age.is_some_and(|max_age| age > max_age as i64 * DAY) {
+                rules[index] = Some(Rule::Age);
+            } else if self.tiered {
+                let tier = TIERS.iter().enumerate().find(|(_, (max_age, _))| age <= *max_age);
+                let first_in_bucket = match tier {
+                    Some((tier, (_, bucket_length))) => tier_buckets.insert((snapshot.filename.as_str(), tier, snapshot.timestamp.div_euclid(*bucket_length))),
+                    None => false,
+                };
+                if !first_in_bucket {
+                    rules[index] = Some(Rule::Tiered);
+                }
+            }
+            *position += 1;
+        }
+        if let Some(max_size) = self.max_size {
+            let max_bytes = max_size as u64 * 1024 * 1024;
+            let (mut total_size, mut full) = (0, false);
+            let mut counted: HashSet<&str> = HashSet::new();
+            for (index, snapshot) in snapshots.iter().enumerate() {
+                if rules[index].is_some() && !protected[index] {
+                    continue;
+                }
+                // objects shared with a newer kept snapshot are already paid for
+                let size = snapshot.size + snapshot.objects.iter()
+                    .filter(|(id, _)| !counted.contains(id.as_str()))
+                    .map(|(_, size)| size)
+                    .sum::<u64>();
+                // kept snapshots fill the budget newest first, everything older than the first misfit goes
+                if rules[index].is_none() && snapshot.label.is_none() && (full || total_size + size > max_bytes) {
+                    full = true;
+                    rules[index] = Some(Rule::Size);
+                }
+                if rules[index].is_none() || protected[index] {
+                    total_size += size;
+                    counted.extend(snapshot.objects.iter().map(|(id, _)| id.as_str()));
+                }
+            }
+        }
+
+        // objects go when no kept snapshot refers to them
+        let kept_objects: HashSet<&str> = snapshots.iter().enumerate()
+            .filter(|(index, _)| rules[*index].is_none() || protected[*index])
+            .flat_map(|(_, snapshot)| snapshot.objects.iter().map(|(id, _)| id.as_str()))
+            .collect();
+        let mut freed_objects: HashMap<&str, u64> = HashMap::new();
+        for (index, snapshot) in snapshots.iter().enumerate() {
+            if rules[index].is_some() && !protected[index] {
+                freed_objects.extend(snapshot.objects.iter()
+                    .filter(|(id, _)| !kept_objects.contains(id.as_str()))
+                    .map(|(id, size)| (id.as_str(), *size)));
+            }
+        }
+        let mut report = RetentionReport { bytes_freed: freed_objects.values().sum(), ..Default::default() };
+
+        for (index, snapshot) in snapshots.iter().enumerate() {
+            let snapshot = snapshot.clone();
+            match (rules[index], protected[index]) {
+                (None, _) => report.kept += 1,
+                (Some(rule), true) => {
+                    report.kept += 1;
+                    report.protected.push(Decision { snapshot, rule });
+                }
+                (Some(rule), false) => {
+                    report.bytes_freed += snapshot.size;
+                    report.removed.push(Decision { snapshot, rule });
+                }
+            }
+        }
+        report
+    }
+}
+
+/// Every snapshot under `history_dir`, or only those of `filename`, with its label, metadata
+/// and objects.
+pub fn list_snapshots(history_dir: &Path, labels: &Labels, filename: Option<&str>) -> Result<Vec<Snapshot>, std::io::Error> {
+    let only = filename;
+    // one user's snapshots refer to a few objects, those are looked up one by one
+    let object_sizes = match only {
+        Some(_) => HashMap::new(),
+        None => object_sizes(history_dir)?,
+    };
+    let object_size = |id: &String| match only {
+        Some(_) => fs::metadata(object_path(history_dir, id)).map(|metadata| metadata.len()).unwrap_or(0),
+        None => object_sizes.get(id).copied().unwrap_or(0),
+    };
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(history_dir)? {
+        let path = entry?.path();
+        let timestamp = match path.file_name().and_then(|name| name.to_str()).and_then(|name| name.parse::<i64>().ok()) {
+            Some(value) if path.is_dir() => value,
+            _ => continue,
+        };
+        for file in fs::read_dir(&path)? {
+            let file = file?;
+            let mut filename = file.file_name().to_string_lossy().into_owned();
+            // sidecars and temp files of unfinished writes
+            if filename.ends_with(META_EXTENSION) || filename.starts_with('.') {
+                continue;
+            }
+            if only.is_some_and(|only| filename != only && filename.strip_suffix(MANIFEST_EXTENSION) != Some(only)) {
+                continue;
+            }
+            let mut objects = Vec::new();
+            if let Some(name) = filename.strip_suffix(MANIFEST_EXTENSION) {
+                // fsck reports it, the rest of the history stays usable
+                match read_manifest(&file.path()) {
+                    Ok(manifest) => objects = manifest.objects()
+                        .map(|id| (id.clone(), object_size(id)))
+                        .collect(),
+                    Err(e) => warn!("Unreadable snapshot manifest: {}", e),
+                }
+                filename = name.to_string();
+            }
+            snapshots.push(Snapshot {
+                timestamp,
+                size: file.metadata()?.len(),
+                label: labels.get(&filename, timestamp).cloned(),
+                meta: read_meta(&path.join(&filename)),
+                filename,
+                objects,
+            });
+        }
+    }
+    Ok(snapshots)
+}
+
+/// Plans and, unless `dry_run`, removes snapshots of `history_dir`. The plan is made on a copy
+/// of `labels`, so labeling is not blocked during the walk.
+pub fn run_in(history_dir: &Path, settings: &RetentionSettings, labels: &Mutex<Labels>, now: i64, dry_run: bool) -> Result<RetentionReport, std::io::Error> {
+    let planned_labels = labels.lock().unwrap().clone();
+    let mut report = settings.plan(&list_snapshots(history_dir, &planned_labels, None)?, now);
+    report.dry_run = dry_run;
+    if dry_run {
+        return Ok(report);
+    }
+    remove_planned(history_dir, &mut report, labels)?;
+    Ok(report)
+}
+
+/// Removes the snapshots the report plans to remove, except those labeled since the plan.
+/// Each removal holds the user's file lock and the object store lock, like a sync writing
+/// a snapshot into the same directory.
+fn remove_planned(history_dir: &Path, report: &mut RetentionReport, labels: &Mutex<Labels>) -> Result<(), std::io::Error> {
+    for decision in std::mem::take(&mut report.removed) {
+        let lock = lock_file(&decision.snapshot.filename);
+        let _file = lock.lock().unwrap();
+        let labels = labels.lock().unwrap();
+        if labels.get(&decision.snapshot.filename, decision.snapshot.timestamp).is_some() {
+            report.kept += 1;
+            continue;
+        }
+        let _store = lock_store();
+        remove_snapshot(history_dir, decision.snapshot.timestamp, &decision.snapshot.filename)?;
+        let snapshot_dir = history_dir.join(decision.snapshot.timestamp.to_string());
+        if fs::read_dir(&snapshot_dir)?.next().is_none() {
+            fs::remove_dir(&snapshot_dir)?;
+        }
+        report.removed.push(decision);
+    }
+    // also objects left behind by earlier runs, so the freed bytes are what really went
+    let removed_size: u64 = report.removed.iter().map(|decision| decision.snapshot.size).sum();
+    report.bytes_freed = removed_size + collect_garbage(history_dir)?;
+    Ok(())
+}
+
+/// Applies the configured retention to the history directory.
+pub fn run(dry_run: bool) -> Result<RetentionReport, std::io::Error> {
+    let config = CONFIG_INSTANCE.load();
+    let settings = config.settings.retention();
+    // unknown labels could pin any snapshot
+    if let Some(e) = LABELS.lock().unwrap().error() {
+        return Err(std::io::Error::other(format!("{}, not pruning", e)));
+    }
+    let now = chrono::Utc::now().timestamp();
+    match config.settings.history_backend {
+        HistoryBackend::Files => run_in(&data_path("history"), &settings, &LABELS, now, dry_run),
+        HistoryBackend::Git => git_history::run_in(&data_path("history"), &settings, &LABELS, now, dry_run),
+    }
+}
+
+/// Runs the retention on a blocking thread and records the metrics.
+pub async fn run_recorded(dry_run: bool) -> Result<RetentionReport, String> {
+    let result = tokio::task::spawn_blocking(move || {
+        let _running = RUNNING.lock().unwrap();
+        let (started_at, started) = (chrono::Utc::now().timestamp(), Instant::now());
+        let result = run(dry_run);
+        if !dry_run {
+            RETENTION_METRICS.lock().unwrap().record(&result, started_at, started.elapsed());
+        }
+        result
+    }).await;
+    match result {
+        Ok(value) => value.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Prunes the history every `interval_seconds`, the first run is right after startup.
+pub async fn worker(interval_seconds: u64) {
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_seconds));
+    loop {
+        interval.tick().await;
+        match run_recorded(false).await {
+            Ok(report) if !report.removed.is_empty() => info!("Retention: {}", report.to_string().replace('\n', "; ")),
+            Ok(_) => {}
+            Err(e) => error!("Retention failed: {}", e),
+        }
+    }
+}
+
+// test module
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{read_snapshot, write_meta, write_snapshot};
+
+    const NOW: i64 = 1_000 * DAY;
+
+    fn snapshot(days_ago: i64, filename: &str, size: u64) -> Snapshot {
+        Snapshot { timestamp: NOW - days_ago * DAY, filename: filename.to_string(), size, label: None, meta: None, objects: Vec::new() }
+    }
+
+    fn removed(report: &RetentionReport) -> Vec<(i64, String, Rule)> {
+        report.removed.iter()
+            .map(|decision| ((NOW - decision.snapshot.timestamp) / DAY, decision.snapshot.filename.clone(), decision.rule))
+            .collect()
+    }
+
+    #[test]
+    fn test_max_keep_is_per_user() {
+        let snapshots = vec![snapshot(1, "alice.json", 1), snapshot(2, "alice.json", 1), snapshot(3, "alice.json", 1), snapshot(3, "bob.json", 1)];
+        let settings = RetentionSettings { max_keep: Some(2), ..Default::default() };
+        assert_eq!(removed(&settings.plan(&snapshots, NOW)), vec![(3, String::from("alice.json"), Rule::Count)]);
+    }
+
+    #[test]
+    fn test_min_keep_beats_max_age() {
+        let snapshots = vec![snapshot(100, "alice.json", 1), snapshot(200, "alice.json", 1), snapshot(300, "alice.json", 1)];
+        let settings = RetentionSettings { min_keep: 2, max_age: Some(90), ..Default::default() };
+        let report = settings.plan(&snapshots, NOW);
+        assert_eq!(removed(&report), vec![(300, String::from("alice.json"), Rule::Age)]);
+        assert_eq!(report.protected.len(), 2);
+        assert_eq!(report.kept, 2);
+    }
+
+    #[test]
+    fn test_max_size_removes_oldest() {
+        let megabyte = 1024 * 1024;
+        let snapshots = vec![snapshot(1, "alice.json", megabyte), snapshot(2, "bob.json", megabyte), snapshot(3, "alice.json", megabyte), snapshot(4, "bob.json", megabyte)];
+        let settings = RetentionSettings { max_size: Some(2), ..Default::default() };
+        let report = settings.plan(&snapshots, NOW);
+        assert_eq!(removed(&report), vec![(3, String::from("alice.json"), Rule::Size), (4, String::from("bob.json"), Rule::Size)]);
+        assert_eq!(report.bytes_freed, 2 * megabyte);
+    }
+
+    #[test]
+    fn test_max_size_counts_shared_objects_once() {
+        let megabyte = 1024 * 1024;
+        let with_objects = |days_ago, ids: &[&str]| Snapshot {
+            objects: ids.iter().map(|id| (id.to_string(), megabyte)).collect(),
+            ..snapshot(days_ago, "alice.json", 0)
+        };
+        // the second snapshot only adds one group, the third shares nothing
+        let snapshots = vec![with_objects(1, &["a", "b"]), with_objects(2, &["a", "b", "c"]), with_objects(3, &["d", "e"])];
+        let settings = RetentionSettings { max_size: Some(3), ..Default::default() };
+        let report = settings.plan(&snapshots, NOW);
+        assert_eq!(removed(&report), vec![(3, String::from("alice.json"), Rule::Size)]);
+        assert_eq!(report.bytes_freed, 2 * megabyte);
+
+        let settings = RetentionSettings { max_keep: Some(1), ..Default::default() };
+        // "a" and "b" stay with the newest snapshot
+        assert_eq!(settings.plan(&snapshots, NOW).bytes_freed, 3 * megabyte);
+    }
+
+    #[test]
+    fn test_rules_combine() {
+        let snapshots = (0..10).map(|days_ago| snapshot(days_ago * 30, "alice.json", 100)).collect::<Vec<Snapshot>>();
+        let settings = RetentionSettings { min_keep: 1, max_keep: Some(8), max_age: Some(150), ..Default::default() };
+        let rules = removed(&settings.plan(&snapshots, NOW)).into_iter().map(|(days_ago, _, rule)| (days_ago, rule)).collect::<Vec<(i64, Rule)>>();
+        assert_eq!(rules, vec![(180, Rule::Age), (210, Rule::Age), (240, Rule::Count), (270, Rule::Count)]);
+    }
+
+    #[test]
+    fn test_labeled_snapshots_are_kept() {
+        let mut snapshots = vec![snapshot(1, "alice.json", 1), snapshot(2, "alice.json", 1), snapshot(300, "alice.json", 1)];
+        snapshots[2].label = Some(String::from("before cleanup"));
+        let settings = RetentionSettings { max_keep: Some(1), max_age: Some(90), max_size: Some(1), tiered: true, ..Default::default() };
+        let report = settings.plan(&snapshots, NOW);
+        // the labeled snapshot does not use up max_keep
+        assert_eq!(removed(&report), vec![(2, String::from("alice.json"), Rule::Count)]);
+        assert_eq!(report.kept, 2);
+    }
+
+    #[test]
+    fn test_tiered() {
+        let mut snapshots = Vec::new();
+        // every 10 minutes for 400 days
+        for minutes_ago in (0..400 * 24 * 60).step_by(10) {
+            snapshots.push(Snapshot { timestamp: NOW - minutes_ago * 60, filename: String::from("alice.json"), size: 1, label: None, meta: None, objects: Vec::new() });
+        }
+        let settings = RetentionSettings { tiered: true, ..Default::default() };
+        let report = settings.plan(&snapshots, NOW);
+        assert!(report.removed.iter().all(|decision| decision.rule == Rule::Tiered));
+
+        let removed = report.removed.iter().map(|decision| decision.snapshot.timestamp).collect::<HashSet<i64>>();
+        let mut kept = snapshots.iter()
+            .filter(|snapshot| !removed.contains(&snapshot.timestamp))
+            .map(|snapshot| NOW - snapshot.timestamp)
+            .collect::<Vec<i64>>();
+        kept.sort();
+        let in_range = |low: i64, high: i64| kept.iter().filter(|age| **age > low && **age <= high).count();
+        assert_eq!(in_range(-1, DAY), 24 * 6 + 1);
+        // one per hour, the buckets are aligned to the clock so the edges may add one
+        assert!((6 * 24..=6 * 24 + 1).contains(&in_range(DAY, 7 * DAY)));
+        assert!((23..=24).contains(&in_range(7 * DAY, 30 * DAY)));
+        assert!((47..=49).contains(&in_range(30 * DAY, 365 * DAY)));
+        assert_eq!(in_range(365 * DAY, 400 * DAY), 0);
+    }
+
+    #[test]
+    fn test_tiered_keeps_last_month_after_burst() {
+        // a sync every minute for 500 minutes yesterday
+        let mut snapshots = (0..500).map(|minutes| Snapshot { timestamp: NOW - 2 * DAY + minutes * 60, filename: String::from("alice.json"), size: 1, label: None, meta: None, objects: Vec::new() }).collect::<Vec<Snapshot>>();
+        snapshots.push(snapshot(40, "alice.json", 1));
+
+        let settings = RetentionSettings { max_keep: Some(100), ..Default::default() };
+        assert!(settings.plan(&snapshots, NOW).removed.iter().any(|decision| decision.snapshot == snapshot(40, "alice.json", 1)));
+
+        let settings = RetentionSettings { tiered: true, ..Default::default() };
+        let report = settings.plan(&snapshots, NOW);
+        assert!(!report.removed.iter().any(|decision| decision.snapshot == snapshot(40, "alice.json", 1)));
+        assert!(report.kept <= 11);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut metrics = RetentionMetrics::default();
+        let report = RetentionReport { removed: vec![Decision { snapshot: snapshot(1, "alice.json", 10), rule: Rule::Count }], bytes_freed: 10, ..Default::default() };
+        metrics.record(&Ok(report), NOW, Duration::from_millis(5));
+        metrics.record(&Err(std::io::Error::other("denied")), NOW + 1, Duration::from_millis(1));
+        assert_eq!(metrics.runs, 2);
+        assert_eq!(metrics.last_run_at, Some(NOW + 1));
+        assert_eq!(metrics.last_removed, 0);
+        assert_eq!(metrics.last_error, Some(String::from("denied")));
+        assert_eq!(metrics.total_removed, 1);
+        assert_eq!(metrics.total_bytes_freed, 10);
+    }
+
+    #[test]
+    fn test_run_in_dry_run_and_remove() {
+        let history_dir = std::env::temp_dir().join(format!("bot-retention-{}", std::process::id()));
+        for (days_ago, filename) in [(1, "alice.json"), (2, "alice.json"), (3, "alice.json")] {
+            let snapshot_dir = history_dir.join((NOW - days_ago * DAY).to_string());
+            fs::create_dir_all(&snapshot_dir).unwrap();
+            fs::write(snapshot_dir.join(filename), "[]").unwrap();
+            write_meta(&snapshot_dir.join(filename), &SnapshotMeta::new("alice", "[]")).unwrap();
+        }
+        let settings = RetentionSettings { max_keep: Some(1), ..Default::default() };
+
+        let labels = Mutex::new(Labels::new());
+        let report = run_in(&history_dir, &settings, &labels, NOW, true).unwrap();
+        assert_eq!(report.removed.len(), 2);
+        let snapshots = list_snapshots(&history_dir, &Labels::new(), None).unwrap();
+        assert_eq!(snapshots.len(), 3);
+        assert!(snapshots.iter().all(|snapshot| snapshot.meta.as_ref().is_some_and(|meta| meta.username == "alice")));
+
+        // labeled after the plan was made
+        let mut report = report;
+        labels.lock().unwrap().set("alice.json", NOW - 2 * DAY, Some(String::from("keep me"))).unwrap();
+        remove_planned(&history_dir, &mut report, &labels).unwrap();
+        assert_eq!(report.removed.len(), 1);
+        assert_eq!(report.bytes_freed, 2);
+        assert_eq!(list_snapshots(&history_dir, &Labels::new(), None).unwrap().len(), 2);
+        // the sidecar went with the snapshot and the empty directory too
+        assert!(!history_dir.join((NOW - 3 * DAY).to_string()).exists());
+        fs::remove_dir_all(&history_dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_in_removes_unreferenced_objects() {
+        let history_dir = std::env::temp_dir().join(format!("bot-retention-objects-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&history_dir);
+        fs::create_dir_all(&history_dir).unwrap();
+        write_snapshot(&history_dir, NOW - DAY, "alice.json", r#"[{"title":"kept"}]"#).unwrap();
+        write_snapshot(&history_dir, NOW - 2 * DAY, "alice.json", r#"[{"title":"kept"},{"title":"old"}]"#).unwrap();
+        let snapshots = list_snapshots(&history_dir, &Labels::new(), None).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert!(snapshots.iter().all(|snapshot| snapshot.filename == "alice.json" && !snapshot.objects.is_empty()));
+        let objects = |snapshots: Vec<Snapshot>| snapshots.into_iter().map(|snapshot| snapshot.objects).collect::<Vec<_>>();
+        assert_eq!(objects(list_snapshots(&history_dir, &Labels::new(), Some("alice.json")).unwrap()), objects(snapshots));
+        assert!(list_snapshots(&history_dir, &Labels::new(), Some("bob.json")).unwrap().is_empty());
+
+        let settings = RetentionSettings { max_keep: Some(1), ..Default::default() };
+        let report = run_in(&history_dir, &settings, &Mutex::new(Labels::new()), NOW, false).unwrap();
+        assert_eq!(report.removed.len(), 1);
+        assert!(report.bytes_freed > report.removed[0].snapshot.size);
+        assert_eq!(object_sizes(&history_dir).unwrap().len(), 1);
+        assert_eq!(read_snapshot(&history_dir, NOW - DAY, "alice.json").unwrap(), r#"[{"title":"kept"}]"#);
+        fs::remove_dir_all(&history_dir).unwrap();
+    }
+}
@@ -1,22 +1,40 @@
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Read, Write};
-use std::path::Path;
-use std::sync::Mutex;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use rand::Rng;
+use log::error;
+use crate::audit;
+use crate::models::tabs::TabGroup;
 use crate::models::user::User;
-use crate::Config;
-use crate::config::RotateType;
-use crate::ip::Ips;
+use crate::config::{HistoryBackend, CONFIG_INSTANCE};
+use crate::git_history;
+use crate::history::{self, meta_path, normalized_hash, read_meta, read_snapshot, snapshot_exists, write_meta, SnapshotMeta};
+use crate::labels::LABELS;
+use crate::retention::{self, Snapshot};
+use crate::oidc::is_valid_username;
 
 lazy_static! {
-    pub static ref INSTANCE: Mutex<Config> = Mutex::new(Config::new());
-    pub static ref IPS_INSTANCE: Mutex<Ips> = Mutex::new(Ips::new());
+    // one writer per tabs file at a time
+    static ref FILE_LOCKS: Mutex<HashMap<String, Arc<Mutex<()>>>> = Mutex::new(HashMap::new());
+    // one read-modify-write of users.txt at a time
+    static ref USERS_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// A file in the configured data directory.
+pub fn data_path(name: &str) -> PathBuf {
+    Path::new(&CONFIG_INSTANCE.load().settings.data_dir).join(name)
 }
 
 pub fn read_lines_from_file(filename: &str) -> Result<Vec<User>, std::io::Error> {
+    read_users(&data_path(filename))
+}
+
+/// The users of a `users.txt`, also of one that is not in the data directory.
+pub fn read_users(path: &Path) -> Result<Vec<User>, std::io::Error> {
     // 打开文件并创建一个 BufReader 来缓冲读取
-    let file = File::open(format!("./data/{}", filename))?;
+    let file = File::open(path)?;
     let reader = BufReader::new(file);
 
     // 准备一个 Vec 来存储行
@@ -25,22 +43,183 @@ pub fn read_lines_from_file(filename: &str) -> Result<Vec<User>, std::io::Error>
     // 遍历每一行并存储到 Vec 中
     for line in reader.lines() {
         let line = line?; // 检查并处理每一行读取可能的错误
-        if line.is_empty() {
-            continue;
+        if let Some(user) = parse_user_line(&line) {
+            lines.push(user);
         }
-        let parts = line.split(",").collect::<Vec<&str>>();
-        if parts.len() != 2 {
-            continue;
-        }
-        lines.push(User {
-            username: String::from(parts[0]),
-            password: String::from(parts[1]),
-        });
     }
 
     Ok(lines) // 返回 Vec
 }
 
+/// `username,password` with an optional `,SG|MY` region lock column
+fn parse_user_line(line: &str) -> Option<User> {
+    if line.is_empty() {
+        return None;
+    }
+    let parts = line.split(",").collect::<Vec<&str>>();
+    if parts.len() != 2 && parts.len() != 3 {
+        return None;
+    }
+    let region_lock = match parts.get(2) {
+        Some(regions) => regions.split("|").filter(|region| !region.is_empty()).map(String::from).collect(),
+        None => Vec::new(),
+    };
+    Some(User {
+        username: String::from(parts[0]),
+        password: String::from(parts[1]),
+        region_lock,
+    })
+}
+
+/// Refuses fields that would split the line or add another one.
+fn format_user_line(user: &User) -> Result<String, std::io::Error> {
+    let breaks_line = |field: &str, separators: &[char]| field.contains(separators) || field.contains(['\n', '\r']);
+    if breaks_line(&user.username, &[',']) || breaks_line(&user.password, &[','])
+        || user.region_lock.iter().any(|region| breaks_line(region, &[',', '|'])) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid users.txt line of {:?}", user.username)));
+    }
+    if user.region_lock.is_empty() {
+        Ok(format!("{},{}", user.username, user.password))
+    } else {
+        Ok(format!("{},{},{}", user.username, user.password, user.region_lock.join("|")))
+    }
+}
+
+/// A line of users.txt, lines that do not parse are written back as they are.
+enum UserLine {
+    User(User),
+    Other(String),
+}
+
+fn find_user<'a>(lines: &'a mut [UserLine], username: &str) -> Option<&'a mut User> {
+    lines.iter_mut().find_map(|line| match line {
+        UserLine::User(user) if user.username == username => Some(user),
+        _ => None,
+    })
+}
+
+/// Changes the users of `path` under the users lock. `update` returns `None` to leave the file
+/// as it is. A missing file has no users.
+fn update_users_in<T>(path: &Path, update: impl FnOnce(&mut Vec<UserLine>) -> Option<T>) -> Result<Option<T>, std::io::Error> {
+    let _guard = USERS_LOCK.lock().unwrap();
+    let contents = match std::fs::read_to_string(path) {
+        Ok(value) => value,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let mut lines = contents.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| match parse_user_line(line) {
+            Some(user) => UserLine::User(user),
+            None => UserLine::Other(line.to_string()),
+        })
+        .collect::<Vec<UserLine>>();
+    let result = match update(&mut lines) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let contents = lines.iter()
+        .map(|line| match line {
+            UserLine::User(user) => format_user_line(user).map(|line| line + "\n"),
+            UserLine::Other(line) => Ok(format!("{}\n", line)),
+        })
+        .collect::<Result<String, std::io::Error>>()?;
+    write_atomic(path, contents.as_bytes())?;
+    Ok(Some(result))
+}
+
+fn update_users<T>(update: impl FnOnce(&mut Vec<UserLine>) -> Option<T>) -> Result<Option<T>, std::io::Error> {
+    update_users_in(&data_path("users.txt"), update)
+}
+
+/// Writes a temp file next to `path`, fsyncs it and renames it over `path`, so readers see
+/// the old or the new contents but never a partial file.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let temp_path = path.with_file_name(format!(".{}.{}.tmp", name, generate_random_string(8)));
+    let result = File::create(&temp_path)
+        .and_then(|mut file| file.write_all(contents).and_then(|()| file.sync_all()))
+        .and_then(|()| std::fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+        return result;
+    }
+    // the rename itself is only durable once the directory is synced
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Held while a tabs file, its sidecar and its history change.
+pub fn lock_file(filename: &str) -> Arc<Mutex<()>> {
+    FILE_LOCKS.lock().unwrap().entry(filename.to_string()).or_default().clone()
+}
+
+/// Regions the user locked their account to, empty when unlocked or unknown.
+pub fn get_user_region_lock(username: &str) -> Vec<String> {
+    match read_lines_from_file("users.txt") {
+        Ok(users) => users.into_iter()
+            .find(|user| user.username == username)
+            .map(|user| user.region_lock)
+            .unwrap_or_default(),
+        Err(_) => Vec::new(),
+    }
+}
+
+pub fn set_user_region_lock(username: &str, regions: Vec<String>) -> Result<bool, std::io::Error> {
+    let updated = update_users(|lines| find_user(lines, username).map(|user| user.region_lock = regions))?;
+    Ok(updated.is_some())
+}
+
+/// Passwords are stored in the csv users.txt, so they can not hold separators.
+pub fn is_valid_password(password: &str) -> bool {
+    !password.is_empty() && !password.contains([',', '\n', '\r'])
+}
+
+pub fn add_user(username: &str, password: &str) -> Result<(), String> {
+    if !is_valid_username(username) {
+        return Err(format!("Invalid username: {}", username));
+    }
+    if !is_valid_password(password) {
+        return Err("Password must not be empty or contain commas or line breaks".to_string());
+    }
+    let added = update_users(|lines| {
+        if find_user(lines, username).is_some() {
+            return None;
+        }
+        lines.push(UserLine::User(User {
+            username: username.to_string(),
+            password: password.to_string(),
+            region_lock: Vec::new(),
+        }));
+        Some(())
+    }).map_err(|e| format!("Error saving file users.txt: {}", e))?;
+    added.ok_or_else(|| format!("User {} already exists", username))
+}
+
+/// Removes the user and their token, tabs and history are kept.
+pub fn remove_user(username: &str) -> Result<bool, std::io::Error> {
+    let removed = update_users(|lines| {
+        let count = lines.len();
+        lines.retain(|line| !matches!(line, UserLine::User(user) if user.username == username));
+        (lines.len() != count).then_some(())
+    })?;
+    if removed.is_none() {
+        return Ok(false);
+    }
+    if data_path(&format!("{}.txt", username)).exists() {
+        remove_user_token(&username.to_string())?;
+    }
+    Ok(true)
+}
+
+pub fn set_user_password(username: &str, password: &str) -> Result<bool, std::io::Error> {
+    let updated = update_users(|lines| find_user(lines, username).map(|user| user.password = password.to_string()))?;
+    Ok(updated.is_some())
+}
+
 pub fn generate_random_string(length: usize) -> String {
     // 定义字符集
     let charset = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
@@ -49,12 +228,12 @@ pub fn generate_random_string(length: usize) -> String {
     let charset_len = charset.len();
 
     // 创建随机生成器
-    let mut rng = rand::thread_rng();
+    let mut rng = rand::rng();
 
     // 生成随机字符串
     let random_string: String = (0..length)
         .map(|_| {
-            let idx = rng.gen_range(0..charset_len);
+            let idx = rng.random_range(0..charset_len);
             charset[idx] as char
         })
         .collect();
@@ -63,171 +242,195 @@ pub fn generate_random_string(length: usize) -> String {
 }
 
 pub fn save_token_to_file(filename: String, token: String) -> Result<(), std::io::Error> {
-    let mut file = File::create(format!("./data/{}", filename))?;
-    file.write_all(token.as_bytes())?;
-    Ok(())
+    write_atomic(&data_path(&filename), token.as_bytes())
 }
 
-fn get_total_files_count_and_remove_old_files() -> Result<u32, std::io::Error> {
-    let instance = INSTANCE.lock().unwrap();
-    let mut removed_count = 0;
-    if instance.settings.rotate_type == RotateType::HistoryCount {
-        let mut files = std::fs::read_dir("./data/history")?
-            .map(|res| res.map(|e| e.path()))
-            .filter(|res| res.is_ok() && res.as_ref().unwrap().is_dir())
-            .collect::<Result<Vec<_>, std::io::Error>>()?;
-        files.sort();
-        if files.len() > instance.settings.rotate_count as usize {
-            for i in 0..files.len() - instance.settings.rotate_count as usize {
-                let path = files[i].as_path();
-                std::fs::remove_dir_all(path)?;
-                removed_count += 1;
-            }
-        }
+/// Stores the current file as a `history/<unix time>/` snapshot, or a commit with the git
+/// backend, and labels it. Returns the snapshot time, `None` when there is no current file.
+pub fn create_snapshot(filename: &str, label: Option<String>) -> Result<Option<i64>, std::io::Error> {
+    let lock = lock_file(filename);
+    let _guard = lock.lock().unwrap();
+    snapshot_current(filename, label)
+}
+
+fn snapshot_current(filename: &str, label: Option<String>) -> Result<Option<i64>, std::io::Error> {
+    if !data_path(filename).exists() {
+        return Ok(None);
     }
-    Ok(removed_count)
-}
-fn get_dir_size<P: AsRef<Path>>(path: P) -> std::io::Result<u64> {
-    let mut size = 0;
-    for entry in std::fs::read_dir(path)? {
-        let entry = entry?;
-        let metadata = entry.metadata()?;
-
-        if metadata.is_dir() {
-            size += get_dir_size(entry.path())?;
-        } else {
-            size += metadata.len();
+
+    let contents = std::fs::read_to_string(data_path(filename))?;
+    let history_dir = data_path("history");
+    let unix_time = match CONFIG_INSTANCE.load().settings.history_backend {
+        HistoryBackend::Git => {
+            let meta = read_meta(&data_path(filename))
+                .unwrap_or_else(|| SnapshotMeta::new(filename.trim_end_matches(".json"), &contents));
+            // the head commit when it already holds the current file
+            git_history::commit(&history_dir, filename, &contents, &meta)?
         }
-    }
-    Ok(size)
-}
-fn get_total_files_size_and_remove_old_files() -> Result<u32, std::io::Error> {
-    let instance = INSTANCE.lock().unwrap();
-    let mut removed_count = 0;
-    if instance.settings.rotate_type == RotateType::TotalSize {
-        let mut files = std::fs::read_dir("./data/history")?
-            .map(|res| res.map(|e| e.path()))
-            .filter(|res| res.is_ok() && res.as_ref().unwrap().is_dir())
-            .collect::<Result<Vec<_>, std::io::Error>>()?;
-        files.sort();
-        let mut total_size = 0;
-        let mut path_size_map: std::collections::HashMap<&Path, u64> = std::collections::HashMap::new();
-
-        for file in &files {
-            let dir_size = get_dir_size(file.clone())?;
-            path_size_map.insert(file.as_path(), dir_size);
-            total_size += dir_size;
+        HistoryBackend::Files => {
+            let meta = read_meta(&data_path(filename));
+            history::snapshot(&history_dir, chrono::Utc::now().timestamp(), filename, &contents, meta.as_ref())?
         }
+    };
+    if label.is_some() {
+        LABELS.lock().unwrap().set(filename, unix_time, label)?;
+    }
+    Ok(Some(unix_time))
+}
 
-        let rotate_size_in_bytes = instance.settings.rotate_size * 1024 * 1024;
+// pruning runs in the retention worker, not on every sync
+fn move_file_to_history(filename: &str) -> Result<(), std::io::Error> {
+    snapshot_current(filename, None)?;
+    Ok(())
+}
 
-        if total_size > rotate_size_in_bytes as u64 {
+/// Snapshots in the history directory, of one file or all, oldest first.
+pub fn list_history(filename: Option<&str>) -> Result<Vec<Snapshot>, std::io::Error> {
+    let labels = LABELS.lock().unwrap().clone();
+    let mut snapshots = match CONFIG_INSTANCE.load().settings.history_backend {
+        HistoryBackend::Files => retention::list_snapshots(&data_path("history"), &labels, filename)?,
+        HistoryBackend::Git => git_history::list_snapshots(&data_path("history"), &labels, filename)?,
+    };
+    snapshots.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then(a.filename.cmp(&b.filename)));
+    Ok(snapshots)
+}
 
-            for file in &files {
-                std::fs::remove_dir_all(file.clone())?;
-                removed_count += 1;
-                let size = path_size_map.get(file.as_path()).unwrap();
-                total_size -= size;
-                if total_size <= rotate_size_in_bytes as u64 {
-                    return Ok(removed_count);
-                }
-            }
-        }
+/// Contents of a snapshot, `None` when there is none.
+pub fn read_history(filename: &str, timestamp: i64) -> Result<Option<String>, std::io::Error> {
+    let history_dir = data_path("history");
+    match CONFIG_INSTANCE.load().settings.history_backend {
+        HistoryBackend::Files if snapshot_exists(&history_dir, timestamp, filename) => read_snapshot(&history_dir, timestamp, filename).map(Some),
+        HistoryBackend::Files => Ok(None),
+        HistoryBackend::Git => git_history::read_snapshot(&history_dir, timestamp, filename),
     }
-    Ok(removed_count)
-}
-
-fn get_total_files_time_and_remove_old_files() -> Result<u32, std::io::Error> {
-    let instance = INSTANCE.lock().unwrap();
-    let mut removed_count = 0;
-    if instance.settings.rotate_type == RotateType::StoredTime {
-        let mut files = std::fs::read_dir("./data/history")?
-            .map(|res| res.map(|e| e.path()))
-            .filter(|res| res.is_ok() && res.as_ref().unwrap().is_dir())
-            .collect::<Result<Vec<_>, std::io::Error>>()?;
-        files.sort();
-        let now = chrono::Utc::now().timestamp();
-
-        let store_time_in_seconds = instance.settings.rotate_time * 24 * 60 * 60;
-
-        for file in &files {
-            let filename = file.file_name().unwrap();
-            let dir_timestamp = i64::from_str_radix(filename.to_str().unwrap(), 10).unwrap();
-
-            if now - dir_timestamp > store_time_in_seconds as i64 {
-                std::fs::remove_dir_all(file.clone())?;
-                removed_count += 1;
-                println!("delete: {:?}", filename);
-            }
-            else{
-                println!("keep: {:?}", filename);
-            }
-        }
-    }
-    Ok(removed_count)
 }
 
-fn move_file_to_history(filename: &String) -> Result<(), std::io::Error> {
+/// Makes a snapshot the current file again, the current file goes to history first.
+pub fn restore_history(filename: &str, timestamp: i64) -> Result<(), std::io::Error> {
+    let contents = read_history(filename, timestamp)?
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("No snapshot {}", timestamp)))?;
+    let mut meta = read_meta(&data_path("history").join(timestamp.to_string()).join(filename))
+        .unwrap_or_else(|| SnapshotMeta::new(filename.trim_end_matches(".json"), &contents));
+    meta.saved_at = chrono::Utc::now().timestamp();
+    save_tabs_to_file(filename, contents, meta, None)?;
+    Ok(())
+}
 
-    if !std::path::Path::new(&format!("./data/{}", filename)).exists() {
-        return Ok(());
+/// Saves the tabs with their metadata, the current file goes to history first. With a label
+/// the saved tabs are also kept as a labeled snapshot. Returns false and keeps the file as it
+/// is when the tabs did not change.
+///
+/// Syncs of the same user run one after the other. The snapshot is durable before the new
+/// file replaces the old one, so a crash in between leaves the old file and a spare snapshot.
+pub fn save_tabs_to_file(filename: &str, tabs: String, meta: SnapshotMeta, label: Option<String>) -> Result<bool, std::io::Error> {
+    let lock = lock_file(filename);
+    let _guard = lock.lock().unwrap();
+    let path = data_path(filename);
+    // hashed from the file, the sidecar is written after it and may lag behind after a crash
+    let current_hash = std::fs::read_to_string(&path).ok().map(|contents| normalized_hash(&contents));
+    let changed = current_hash.as_deref() != Some(meta.hash.as_str());
+    if changed {
+        move_file_to_history(filename)?;
+        write_atomic(&path, tabs.as_bytes())?;
+        write_meta(&path, &meta)?;
+        if CONFIG_INSTANCE.load().settings.history_backend == HistoryBackend::Git {
+            git_history::commit(&data_path("history"), filename, &tabs, &meta)?;
+        }
     }
-
-    let unix_time = chrono::Utc::now().timestamp();
-    let history_dir = format!("./data/history/{}", unix_time);
-    if !std::path::Path::new(&history_dir).exists() {
-        std::fs::create_dir(history_dir)?;
-        std::fs::copy(format!("./data/{}", filename), format!("./data/history/{}/{}", unix_time, filename))?;
+    // still under the lock, so the label lands on these tabs and not on a later sync
+    if label.is_some() {
+        snapshot_current(filename, label)?;
     }
+    Ok(changed)
+}
 
-    get_total_files_count_and_remove_old_files()?;
-    get_total_files_time_and_remove_old_files()?;
-    get_total_files_size_and_remove_old_files()?;
-
-    Ok(())
+/// Tabs of the current file, `recovered_from` is set when the file was corrupt and the
+/// snapshot of that time took its place.
+pub struct LoadedTabs {
+    pub tabs: Vec<TabGroup>,
+    pub recovered_from: Option<i64>,
 }
 
+/// A file that is not utf-8 or not tabs json fails with `InvalidData`, only that is corruption.
+/// Other errors, e.g. permissions or too many open files, leave the file alone.
+fn parse_tabs_file(path: &Path) -> Result<Vec<TabGroup>, std::io::Error> {
+    let contents = std::fs::read_to_string(path)?;
+    // serde_json reports a cut off file as UnexpectedEof
+    serde_json::from_str(&contents).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
 
-pub fn save_tabs_to_file(filename: &String, tabs: String) -> Result<(), std::io::Error> {
-    move_file_to_history(filename)?;
-    let mut file = File::create(&format!("./data/{}", filename))?;
-    file.write_all(tabs.as_bytes())?;
-    Ok(())
+/// Moves a tabs file and its sidecar to `quarantine/`, returns where the file went.
+fn quarantine(filename: &str) -> Result<PathBuf, std::io::Error> {
+    let quarantine_dir = data_path("quarantine");
+    std::fs::create_dir_all(&quarantine_dir)?;
+    let path = quarantine_dir.join(format!("{}.{}", filename, chrono::Utc::now().timestamp()));
+    std::fs::rename(data_path(filename), &path)?;
+    if meta_path(&data_path(filename)).exists() {
+        std::fs::rename(meta_path(&data_path(filename)), meta_path(&path))?;
+    }
+    Ok(path)
 }
 
-pub fn get_tabs_from_file(filename: String) -> Result<String, std::io::Error> {
-    let file = File::open(format!("./data/{}", filename));
-    match file {
-        Ok(mut f) => {
-            let mut contents = String::new();
-            f.read_to_string(&mut contents)?;
-            return Ok(contents);
-        }
-        Err(e) => {
-            return Err(e);
+/// Reads the current tabs. A file that can not be parsed is quarantined and the newest
+/// snapshot that parses becomes the current file, which is recorded in the audit log.
+pub fn load_tabs(filename: &str) -> Result<LoadedTabs, std::io::Error> {
+    match parse_tabs_file(&data_path(filename)) {
+        Ok(tabs) => return Ok(LoadedTabs { tabs, recovered_from: None }),
+        Err(e) if e.kind() != std::io::ErrorKind::InvalidData => return Err(e),
+        Err(_) => {}
+    }
+    let lock = lock_file(filename);
+    let _guard = lock.lock().unwrap();
+    // another request may have recovered it meanwhile
+    let corruption = match parse_tabs_file(&data_path(filename)) {
+        Ok(tabs) => return Ok(LoadedTabs { tabs, recovered_from: None }),
+        Err(e) if e.kind() != std::io::ErrorKind::InvalidData => return Err(e),
+        Err(e) => e,
+    };
+    let username = filename.trim_end_matches(".json");
+    let quarantined = quarantine(filename)?;
+    error!("Tabs file {} is corrupt, moved to {}: {}", filename, quarantined.display(), corruption);
+
+    for snapshot in list_history(Some(filename))?.into_iter().rev() {
+        let contents = match read_history(filename, snapshot.timestamp) {
+            Ok(Some(value)) => value,
+            _ => continue,
+        };
+        if let Ok(tabs) = serde_json::from_str::<Vec<TabGroup>>(&contents) {
+            write_atomic(&data_path(filename), contents.as_bytes())?;
+            write_meta(&data_path(filename), &SnapshotMeta::new(username, &contents))?;
+            error!("Recovered tabs of {} from snapshot {}", username, snapshot.timestamp);
+            audit::record("tabs_recovered", username, serde_json::json!({
+                "error": corruption.to_string(),
+                "quarantined": quarantined.to_string_lossy(),
+                "snapshot": snapshot.timestamp,
+            }));
+            return Ok(LoadedTabs { tabs, recovered_from: Some(snapshot.timestamp) });
         }
     }
+    audit::record("tabs_recovery_failed", username, serde_json::json!({
+        "error": corruption.to_string(),
+        "quarantined": quarantined.to_string_lossy(),
+    }));
+    Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} is corrupt and no snapshot could be read: {}", filename, corruption)))
 }
 
 pub fn try_get_username_token(username: &String, token: String) -> bool {
     let filename = format!("{}.txt", username);
-    let file = File::open(format!("./data/{}", filename));
-    match file {
-        Ok(mut f) => {
-            let mut contents = String::new();
-            f.read_to_string(&mut contents).unwrap();
-            if contents == token {
-                return true;
-            }
+    let file = File::open(data_path(&filename));
+    if let Ok(mut f) = file {
+        let mut contents = String::new();
+        f.read_to_string(&mut contents).unwrap();
+        if contents == token {
+            return true;
         }
-        Err(_) => {}
     }
     false
 }
 
 pub fn remove_user_token(username: &String) -> Result<(), std::io::Error> {
     let filename = format!("{}.txt", username);
-    std::fs::remove_file(format!("./data/{}", filename))?;
+    std::fs::remove_file(data_path(&filename))?;
     Ok(())
 }
 
@@ -236,36 +439,93 @@ pub fn remove_user_token(username: &String) -> Result<(), std::io::Error> {
 mod tests {
     use super::*;
     #[test]
-    fn test_get_total_files_count_and_remove_old_files() {
-        let deleted = match get_total_files_count_and_remove_old_files() {
-            Ok(value) => value,
-            Err(_) => {
-                assert!(false);
-                return;
-            }
-        };
-        assert_eq!(deleted, 0);
+    fn test_parse_user_line() {
+        let user = parse_user_line("alice,secret").unwrap();
+        assert_eq!(user.username, "alice");
+        assert!(user.region_lock.is_empty());
+
+        let user = parse_user_line("alice,secret,SG|MY").unwrap();
+        assert_eq!(user.region_lock, vec!["SG".to_string(), "MY".to_string()]);
+        assert_eq!(format_user_line(&user).unwrap(), "alice,secret,SG|MY");
+        let user = User { region_lock: vec![String::from("SG\nadmin,pw")], ..user };
+        assert!(format_user_line(&user).is_err());
+
+        assert!(parse_user_line("").is_none());
+        assert!(parse_user_line("alice").is_none());
     }
+
     #[test]
-    fn test_get_total_files_size_and_remove_old_files() {
-        let deleted = match get_total_files_size_and_remove_old_files() {
-            Ok(value) => value,
-            Err(_) => {
-                assert!(false);
-                return;
-            }
-        };
-        assert_eq!(deleted, 0);
+    fn test_is_valid_password() {
+        assert!(is_valid_password("secret"));
+        assert!(!is_valid_password(""));
+        assert!(!is_valid_password("se,cret"));
+        assert!(!is_valid_password("secret\n"));
     }
+
     #[test]
-    fn test_get_total_files_time_and_remove_old_files() {
-        let deleted = match get_total_files_time_and_remove_old_files() {
-            Ok(value) => value,
-            Err(_) => {
-                assert!(false);
-                return;
-            }
-        };
-        assert_eq!(deleted, 0);
+    fn test_update_users_keeps_other_lines() {
+        let path = std::env::temp_dir().join(format!("bot-users-{}.txt", std::process::id()));
+        std::fs::write(&path, "alice,secret\n# admins\nbob,secret,SG\n").unwrap();
+        let updated = update_users_in(&path, |lines| find_user(lines, "bob").map(|user| user.region_lock.clear())).unwrap();
+        assert!(updated.is_some());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "alice,secret\n# admins\nbob,secret\n");
+        assert!(update_users_in(&path, |lines| find_user(lines, "carol").map(|_| ())).unwrap().is_none());
+
+        // concurrent updates do not lose each other
+        let threads = (0..8).map(|i| {
+            let path = path.clone();
+            std::thread::spawn(move || update_users_in(&path, |lines| {
+                lines.push(UserLine::User(User { username: format!("user{}", i), password: String::from("secret"), region_lock: Vec::new() }));
+                Some(())
+            }).unwrap())
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(read_users(&path).unwrap().len(), 10);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = std::env::temp_dir().join(format!("bot-atomic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("alice.json");
+        write_atomic(&path, b"[1]").unwrap();
+        write_atomic(&path, b"[1,2]").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "[1,2]");
+        // no temp file is left behind
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(write_atomic(&dir.join("missing").join("alice.json"), b"[]").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_tabs_file_only_reports_corruption_as_invalid_data() {
+        let dir = std::env::temp_dir().join(format!("bot-parse-tabs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("alice.json");
+        std::fs::write(&path, "[]").unwrap();
+        assert!(parse_tabs_file(&path).unwrap().is_empty());
+        for contents in [&b"[{\"uuid\":"[..], b"{}", b"[\xff]"] {
+            std::fs::write(&path, contents).unwrap();
+            assert_eq!(parse_tabs_file(&path).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        }
+        // not corruption, the file must stay where it is
+        assert_ne!(parse_tabs_file(&dir).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert_ne!(parse_tabs_file(&dir.join("bob.json")).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lock_file_is_per_file() {
+        assert!(Arc::ptr_eq(&lock_file("alice.json"), &lock_file("alice.json")));
+        assert!(!Arc::ptr_eq(&lock_file("alice.json"), &lock_file("bob.json")));
+    }
+
+    #[test]
+    fn test_list_history() {
+        let snapshots = list_history(None).unwrap();
+        assert!(snapshots.windows(2).all(|pair| pair[0].timestamp <= pair[1].timestamp));
     }
 }
\ No newline at end of file